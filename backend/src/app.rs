@@ -1,47 +1,96 @@
 use crate::{
-    database::Database,
+    database::{
+        models::{attachment, user::Presence},
+        Database,
+    },
+    notifier::{file::FileNotifier, Notifier},
     routes::{self, ws::ws_handler},
-    util::config::ApiConfig,
+    storage::{local::LocalStorage, Storage},
+    util::{config::ApiConfig, login_limiter::LoginLimiter},
 };
 use axum::{routing::get, Router};
 use dashmap::DashMap;
 use http::{header, Method};
 use std::sync::Arc;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, Notify};
 use tower_http::{cors::CorsLayer, trace::TraceLayer};
 use tracing::warn;
 
 #[derive(Default)]
 pub struct UserSocket {
+    /// whether any client is connected, see `visible_presence` for what other users see.
     pub online: bool,
+    pub presence: Presence,
     pub last_seen_s: Option<u64>,
-    pub channel: Vec<mpsc::UnboundedSender<String>>,
+    pub channel: Vec<SocketChannel>,
     pub chats: Vec<String>,
 }
 
+/// a single connected client.
+pub struct SocketChannel {
+    pub sender: mpsc::UnboundedSender<String>,
+    /// the session the client authenticated with.
+    pub session_id: String,
+    /// notified when the session is revoked, the connection is closed after that.
+    pub close: Arc<Notify>,
+}
+
 impl UserSocket {
     pub fn send_json(&self, data: &serde_json::Value) {
         for channel in &self.channel {
-            if let Err(err) = channel.send(data.to_string()) {
+            if let Err(err) = channel.sender.send(data.to_string()) {
                 warn!("Failed to send JSON data to client: {}", err);
             }
         }
     }
+
+    /// the presence other users see, `None` if the user appears offline.
+    pub fn visible_presence(&self) -> Option<Presence> {
+        if self.online && self.presence != Presence::Invisible {
+            Some(self.presence)
+        } else {
+            None
+        }
+    }
+
+    /// closes every client that authenticated with one of `session_ids`.
+    pub fn close_sessions(&self, session_ids: &[String]) {
+        for channel in &self.channel {
+            if session_ids.contains(&channel.session_id) {
+                channel.close.notify_one();
+            }
+        }
+    }
 }
 #[derive(Clone)]
 pub struct AppState {
     pub db: Database,
+    pub config: Arc<ApiConfig>,
+    pub storage: Arc<dyn Storage>,
+    pub notifier: Arc<dyn Notifier>,
+    pub login_limiter: Arc<LoginLimiter>,
     pub sockets: Arc<DashMap<String, UserSocket>>,
     pub chats: Arc<DashMap<String, Vec<String>>>,
 }
 
 pub async fn build(config: &ApiConfig) -> Result<Router<()>, mongodb::error::Error> {
     let db = Database::connect(config).await?;
+    let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(config.upload_dir.clone()));
+    tokio::spawn(attachment::sweep_unsent_attachments(
+        db.clone(),
+        storage.clone(),
+    ));
+
     let state = AppState {
         db,
+        config: Arc::new(config.clone()),
+        storage,
+        notifier: Arc::new(FileNotifier::new(config.notification_file.clone())),
+        login_limiter: Arc::new(LoginLimiter::new(config)),
         sockets: Arc::new(DashMap::new()),
         chats: Arc::new(DashMap::new()),
     };
+    tokio::spawn(routes::ws::persist_last_seen(state.clone()));
 
     Ok(Router::new()
         .nest("/auth", routes::auth::build_router())
@@ -53,7 +102,13 @@ pub async fn build(config: &ApiConfig) -> Result<Router<()>, mongodb::error::Err
             CorsLayer::new()
                 .allow_credentials(true)
                 .allow_origin(config.cors_origins.to_owned())
-                .allow_methods([Method::GET, Method::POST, Method::DELETE, Method::PUT])
+                .allow_methods([
+                    Method::GET,
+                    Method::POST,
+                    Method::DELETE,
+                    Method::PUT,
+                    Method::PATCH,
+                ])
                 .allow_headers([header::CONTENT_TYPE, header::AUTHORIZATION]),
         )
         .with_state(state))
@@ -1,5 +1,10 @@
 pub mod models;
-use mongodb::{bson::doc, options::ClientOptions, Client, Collection, Database as MongoDatabase};
+use futures_util::TryStreamExt;
+use mongodb::{
+    bson::{doc, Document},
+    options::{ClientOptions, IndexOptions},
+    Client, Collection, Database as MongoDatabase, IndexModel,
+};
 
 use crate::util::config::ApiConfig;
 
@@ -22,6 +27,18 @@ impl Database {
     fn messages<T>(&self) -> Collection<T> {
         self.db.collection("messages")
     }
+    fn attachments<T>(&self) -> Collection<T> {
+        self.db.collection("attachments")
+    }
+    fn password_resets<T>(&self) -> Collection<T> {
+        self.db.collection("password_resets")
+    }
+    fn mfa_challenges<T>(&self) -> Collection<T> {
+        self.db.collection("mfa_challenges")
+    }
+    fn invites<T>(&self) -> Collection<T> {
+        self.db.collection("invites")
+    }
     pub async fn connect(config: &ApiConfig) -> Result<Database, mongodb::error::Error> {
         let client_options = ClientOptions::parse(&config.database_url).await?;
 
@@ -34,6 +51,123 @@ impl Database {
             .await?;
 
         tracing::info!("connected to mongodb");
-        Ok(Database { client, db })
+        let database = Database { client, db };
+        database.migrate_session_tokens(config).await?;
+        database.create_indexes().await?;
+        Ok(database)
+    }
+
+    /// replaces the plaintext tokens of sessions created before tokens were hashed.
+    async fn migrate_session_tokens(
+        &self,
+        config: &ApiConfig,
+    ) -> Result<(), mongodb::error::Error> {
+        let sessions = self.sessions::<Document>();
+        let mut cursor = sessions
+            .find(doc! { "token": { "$exists": true } }, None)
+            .await?;
+
+        let mut migrated = 0;
+        while let Some(session) = cursor.try_next().await? {
+            let (Ok(id), Ok(token)) = (session.get_str("_id"), session.get_str("token")) else {
+                continue;
+            };
+            sessions
+                .update_one(
+                    doc! { "_id": id },
+                    doc! {
+                        "$set": { "tokenHash": models::session::hash_token(config, token) },
+                        "$unset": { "token": "" }
+                    },
+                    None,
+                )
+                .await?;
+            migrated += 1;
+        }
+
+        if migrated > 0 {
+            tracing::info!("hashed the tokens of {migrated} existing sessions");
+        }
+        Ok(())
+    }
+
+    /// creates the indexes the queries rely on. existing indexes are left untouched.
+    async fn create_indexes(&self) -> Result<(), mongodb::error::Error> {
+        self.messages::<Document>()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "content": "text" })
+                    .build(),
+                None,
+            )
+            .await?;
+        self.sessions::<Document>()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "tokenHash": 1 })
+                    .options(IndexOptions::builder().unique(true).build())
+                    .build(),
+                None,
+            )
+            .await?;
+        // expired sessions are removed by mongodb once `expiresAt` has passed.
+        self.sessions::<Document>()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "expiresAt": 1 })
+                    .options(
+                        IndexOptions::builder()
+                            .expire_after(std::time::Duration::from_secs(0))
+                            .build(),
+                    )
+                    .build(),
+                None,
+            )
+            .await?;
+
+        self.password_resets::<Document>()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "expiresAt": 1 })
+                    .options(
+                        IndexOptions::builder()
+                            .expire_after(std::time::Duration::from_secs(0))
+                            .build(),
+                    )
+                    .build(),
+                None,
+            )
+            .await?;
+
+        self.mfa_challenges::<Document>()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "expiresAt": 1 })
+                    .options(
+                        IndexOptions::builder()
+                            .expire_after(std::time::Duration::from_secs(0))
+                            .build(),
+                    )
+                    .build(),
+                None,
+            )
+            .await?;
+
+        self.invites::<Document>()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "expiresAt": 1 })
+                    .options(
+                        IndexOptions::builder()
+                            .expire_after(std::time::Duration::from_secs(0))
+                            .build(),
+                    )
+                    .build(),
+                None,
+            )
+            .await?;
+
+        tracing::info!("created mongodb indexes");
+        Ok(())
     }
 }
@@ -0,0 +1,229 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Context;
+use futures_util::TryStreamExt;
+use mongodb::{
+    bson::{doc, DateTime},
+    options::FindOptions,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use ulid::Ulid;
+
+use crate::{
+    database::Database,
+    storage::Storage,
+    util::{
+        constants::{ATTACHMENT_SWEEP_INTERVAL_S, UNSENT_ATTACHMENT_LIFETIME_MS},
+        permissions::ChatPermissions,
+        result::{ApiError, ApiResult},
+    },
+};
+
+use super::chat;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Attachment {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub chat_id: String,
+    pub uploader_id: String,
+    pub filename: String,
+    pub content_type: String,
+    pub size: u64,
+    /// set once the attachment is sent with a message.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+}
+
+/// attachment metadata embedded in messages and profiles.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageAttachment {
+    pub id: String,
+    pub filename: String,
+    pub content_type: String,
+    pub size: u64,
+}
+
+impl From<Attachment> for MessageAttachment {
+    fn from(attachment: Attachment) -> Self {
+        MessageAttachment {
+            id: attachment.id,
+            filename: attachment.filename,
+            content_type: attachment.content_type,
+            size: attachment.size,
+        }
+    }
+}
+
+/// stores the file and its metadata. the attachment is not visible to others until it's sent with a message.
+pub async fn create_attachment(
+    db: &Database,
+    storage: &dyn Storage,
+    uploader_id: &str,
+    chat_id: &str,
+    filename: &str,
+    content_type: &str,
+    data: &[u8],
+) -> ApiResult<Attachment> {
+    let attachment = Attachment {
+        id: Ulid::new().to_string(),
+        chat_id: chat_id.to_string(),
+        uploader_id: uploader_id.to_string(),
+        filename: filename.to_string(),
+        content_type: content_type.to_string(),
+        size: data.len() as u64,
+        message_id: None,
+    };
+
+    storage.put(&attachment.id, data).await?;
+
+    if let Err(err) = db
+        .attachments::<Attachment>()
+        .insert_one(&attachment, None)
+        .await
+    {
+        storage.delete(&attachment.id).await.ok();
+        return Err(anyhow::Error::from(err)
+            .context("create_attachment: Failed to insert attachment.")
+            .into());
+    }
+
+    Ok(attachment)
+}
+
+/// finds attachments uploaded by `uploader_id` in `chat_id` that weren't sent with a message yet.
+pub async fn find_unsent_attachments(
+    db: &Database,
+    uploader_id: &str,
+    chat_id: &str,
+    attachment_ids: &[String],
+) -> ApiResult<Vec<Attachment>> {
+    let attachments = db
+        .attachments::<Attachment>()
+        .find(
+            doc! {
+                "_id": { "$in": attachment_ids },
+                "chatId": chat_id,
+                "uploaderId": uploader_id,
+                "messageId": { "$exists": false }
+            },
+            None,
+        )
+        .await
+        .context("find_unsent_attachments: Failed to find attachments.")?
+        .try_collect::<Vec<_>>()
+        .await
+        .context("find_unsent_attachments: Failed to iterate over cursor.")?;
+
+    Ok(attachments)
+}
+
+/// returns the attachment metadata and its contents if `user_id` can read it.
+pub async fn get_attachment(
+    db: &Database,
+    storage: &dyn Storage,
+    user_id: &str,
+    chat_id: &str,
+    attachment_id: &str,
+) -> ApiResult<(Attachment, Vec<u8>)> {
+    let (_, permissions) = chat::find_chat_with_permissions(db, chat_id, user_id).await?;
+    permissions.require(ChatPermissions::READ_MESSAGES)?;
+
+    let attachment = db
+        .attachments::<Attachment>()
+        .find_one(
+            doc! {
+                "_id": attachment_id,
+                "chatId": chat_id
+            },
+            None,
+        )
+        .await
+        .context("get_attachment: Failed to find attachment.")?
+        .ok_or(ApiError::AttachmentNotFound)?;
+
+    // unsent attachments are only visible to the uploader.
+    if attachment.message_id.is_none() && attachment.uploader_id != user_id {
+        return Err(ApiError::AttachmentNotFound);
+    }
+
+    let data = storage
+        .get(&attachment.id)
+        .await?
+        .ok_or(ApiError::AttachmentNotFound)?;
+
+    Ok((attachment, data))
+}
+
+/// removes the stored files of attachments whose metadata is already gone.
+/// failures are only logged, the caller's changes are committed at this point.
+pub async fn delete_attachment_files(storage: &dyn Storage, attachment_ids: &[String]) {
+    for attachment_id in attachment_ids {
+        if let Err(err) = storage.delete(attachment_id).await {
+            warn!(
+                "Failed to delete attachment file {attachment_id}: {:?}",
+                err
+            );
+        }
+    }
+}
+
+/// deletes attachments that weren't sent with a message within `UNSENT_ATTACHMENT_LIFETIME_MS`.
+pub async fn delete_unsent_attachments(db: &Database, storage: &dyn Storage) -> ApiResult<u64> {
+    let cutoff = DateTime::now().timestamp_millis() - UNSENT_ATTACHMENT_LIFETIME_MS;
+    // attachment ids are ulids, so older attachments sort before the cutoff id.
+    let cutoff_id = Ulid::from_parts(cutoff.max(0) as u64, 0).to_string();
+
+    let attachment_ids: Vec<String> = db
+        .attachments::<Attachment>()
+        .find(
+            doc! {
+                "_id": { "$lt": cutoff_id },
+                "messageId": { "$exists": false }
+            },
+            FindOptions::builder().limit(1000).build(),
+        )
+        .await
+        .context("delete_unsent_attachments: Failed to find attachments.")?
+        .map_ok(|attachment| attachment.id)
+        .try_collect()
+        .await
+        .context("delete_unsent_attachments: Failed to iterate over cursor.")?;
+
+    let mut deleted = 0;
+    for attachment_id in attachment_ids {
+        // the attachment may have been sent since it was found, so the filter is checked again.
+        let result = db
+            .attachments::<Attachment>()
+            .delete_one(
+                doc! {
+                    "_id": &attachment_id,
+                    "messageId": { "$exists": false }
+                },
+                None,
+            )
+            .await
+            .context("delete_unsent_attachments: Failed to delete attachment.")?;
+        if result.deleted_count == 1 {
+            delete_attachment_files(storage, &[attachment_id]).await;
+            deleted += 1;
+        }
+    }
+    Ok(deleted)
+}
+
+/// periodically removes unsent attachments, runs for the lifetime of the server.
+pub async fn sweep_unsent_attachments(db: Database, storage: Arc<dyn Storage>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(ATTACHMENT_SWEEP_INTERVAL_S));
+    loop {
+        interval.tick().await;
+        match delete_unsent_attachments(&db, storage.as_ref()).await {
+            Ok(0) => {}
+            Ok(deleted) => info!("deleted {deleted} unsent attachments"),
+            Err(err) => warn!("Failed to delete unsent attachments: {:?}", err),
+        }
+    }
+}
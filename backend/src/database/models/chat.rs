@@ -1,36 +1,80 @@
+use std::fmt;
+
 use anyhow::Context;
-use futures_util::TryStreamExt;
-use mongodb::bson::doc;
+use futures_util::{FutureExt, TryStreamExt};
+use mongodb::{
+    bson::{doc, Document},
+    options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument, UpdateOptions},
+    ClientSession, Collection,
+};
 use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use crate::{
+    database::Database,
+    util::{
+        permissions::{resolve_chat_permissions, ChatPermissions},
+        result::{ApiError, ApiResult},
+    },
+};
 
-use crate::{database::Database, util::result::ApiResult};
+use super::user::{self, RelationStatus};
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Chat {
-    #[serde(rename(deserialize = "_id"))]
+    #[serde(rename = "_id")]
     pub id: String,
     pub chat_type: ChatType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// only set for group chats.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner_id: Option<String>,
     pub recipients: Vec<ChatRecipient>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub last_message_id: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
 pub struct ChatRecipient {
     pub id: String,
+    /// only set for group chats.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<ChatRole>,
+    /// id of the newest message this recipient has read.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_read_id: Option<String>,
 }
-#[derive(Debug, Serialize, Deserialize)]
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum ChatRole {
+    Owner,
+    Moderator,
+    Member,
+}
+
+impl fmt::Display for ChatRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChatRole::Owner => write!(f, "Owner"),
+            ChatRole::Moderator => write!(f, "Moderator"),
+            ChatRole::Member => write!(f, "Member"),
+        }
+    }
+}
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub enum ChatType {
     Direct,
     Group,
 }
 
-impl ToString for ChatType {
-    fn to_string(&self) -> String {
+impl fmt::Display for ChatType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            ChatType::Direct => "Direct".to_string(),
-            ChatType::Group => "Group".to_string(),
+            ChatType::Direct => write!(f, "Direct"),
+            ChatType::Group => write!(f, "Group"),
         }
     }
 }
@@ -54,3 +98,487 @@ pub async fn get_chats_of_user(db: &Database, user_id: &str) -> ApiResult<Vec<Ch
 
     Ok(chats)
 }
+
+pub async fn find_chat_by_id(db: &Database, chat_id: &str) -> ApiResult<Option<Chat>> {
+    let chat = db
+        .chats::<Chat>()
+        .find_one(
+            doc! {
+                "_id": chat_id
+            },
+            None,
+        )
+        .await
+        .context("find_chat_by_id: Failed to find chat.")?;
+
+    Ok(chat)
+}
+
+/// finds the chat and resolves the permissions `user_id` has in it.
+pub async fn find_chat_with_permissions(
+    db: &Database,
+    chat_id: &str,
+    user_id: &str,
+) -> ApiResult<(Chat, ChatPermissions)> {
+    let chat = find_chat_by_id(db, chat_id)
+        .await?
+        .ok_or(ApiError::ChatNotFound)?;
+
+    let relations = if chat.chat_type == ChatType::Direct {
+        user::find_relations_of_user(db, user_id).await?
+    } else {
+        vec![]
+    };
+    let permissions = resolve_chat_permissions(&chat, user_id, &relations);
+
+    Ok((chat, permissions))
+}
+
+/// creates a group chat owned by `owner_id`. every recipient must be a friend of the owner.
+pub async fn create_group_chat(
+    db: &Database,
+    owner_id: &str,
+    name: &str,
+    recipient_ids: &[String],
+) -> ApiResult<Chat> {
+    let mut member_ids: Vec<&String> = vec![];
+    for recipient_id in recipient_ids {
+        if recipient_id != owner_id && !member_ids.contains(&recipient_id) {
+            member_ids.push(recipient_id);
+        }
+    }
+    if member_ids.is_empty() {
+        return Err(ApiError::EmptyGroupChat);
+    }
+
+    let relations = user::find_relations_of_user(db, owner_id).await?;
+
+    let mut recipients = vec![ChatRecipient {
+        id: owner_id.to_string(),
+        role: Some(ChatRole::Owner),
+        last_read_id: None,
+    }];
+    for recipient_id in member_ids {
+        if !relations
+            .iter()
+            .any(|r| r.id == *recipient_id && r.status == RelationStatus::Friend)
+        {
+            return Err(ApiError::NotFriends);
+        }
+        recipients.push(ChatRecipient {
+            id: recipient_id.to_owned(),
+            role: Some(ChatRole::Member),
+            last_read_id: None,
+        });
+    }
+
+    let chat = Chat {
+        id: Ulid::new().to_string(),
+        chat_type: ChatType::Group,
+        name: Some(name.trim().to_string()),
+        owner_id: Some(owner_id.to_string()),
+        recipients,
+        last_message_id: None,
+    };
+
+    db.chats::<Chat>()
+        .insert_one(&chat, None)
+        .await
+        .context("create_group_chat: Failed to insert chat.")?;
+
+    Ok(chat)
+}
+
+/// result of a member leaving a group chat.
+pub struct LeaveGroupResult {
+    pub chat_id: String,
+    /// set when the leaving member was the owner and ownership moved to someone else.
+    pub new_owner_id: Option<String>,
+    /// true when the last member left and the chat was deleted.
+    pub deleted: bool,
+    /// attachments of the deleted chat, their files have to be removed from storage.
+    pub attachment_ids: Vec<String>,
+}
+
+async fn find_group_chat(
+    db: &Database,
+    chat_id: &str,
+    user_id: &str,
+) -> ApiResult<(Chat, ChatPermissions)> {
+    let (chat, permissions) = find_chat_with_permissions(db, chat_id, user_id).await?;
+
+    if chat.chat_type != ChatType::Group {
+        return Err(ApiError::NotGroupChat);
+    }
+    Ok((chat, permissions))
+}
+
+fn role_of(chat: &Chat, user_id: &str) -> Option<ChatRole> {
+    chat.recipients
+        .iter()
+        .find(|r| r.id == user_id)
+        .map(|r| r.role.to_owned().unwrap_or(ChatRole::Member))
+}
+
+async fn update_group_chat(
+    db: &Database,
+    filter: Document,
+    update: Document,
+    array_filters: Option<Vec<Document>>,
+) -> ApiResult<Option<Chat>> {
+    let chat = db
+        .chats::<Chat>()
+        .find_one_and_update(
+            filter,
+            update,
+            FindOneAndUpdateOptions::builder()
+                .array_filters(array_filters)
+                .return_document(ReturnDocument::After)
+                .build(),
+        )
+        .await
+        .context("update_group_chat: Failed to update chat.")?;
+
+    Ok(chat)
+}
+
+pub async fn add_group_recipient(
+    db: &Database,
+    chat_id: &str,
+    actor_id: &str,
+    user_id: &str,
+) -> ApiResult<Chat> {
+    let (chat, permissions) = find_group_chat(db, chat_id, actor_id).await?;
+    permissions.require(ChatPermissions::MANAGE_MEMBERS)?;
+
+    if role_of(&chat, user_id).is_some() {
+        return Err(ApiError::AlreadyChatRecipient);
+    }
+
+    let relations = user::find_relations_of_user(db, actor_id).await?;
+    if !relations
+        .iter()
+        .any(|r| r.id == user_id && r.status == RelationStatus::Friend)
+    {
+        return Err(ApiError::NotFriends);
+    }
+
+    update_group_chat(
+        db,
+        doc! {
+            "_id": chat_id,
+            "recipients.id": { "$ne": user_id }
+        },
+        doc! {
+            "$push": {
+                "recipients": {
+                    "id": user_id,
+                    "role": ChatRole::Member.to_string()
+                }
+            }
+        },
+        None,
+    )
+    .await?
+    .ok_or(ApiError::AlreadyChatRecipient)
+}
+
+pub async fn remove_group_recipient(
+    db: &Database,
+    chat_id: &str,
+    actor_id: &str,
+    user_id: &str,
+) -> ApiResult<Chat> {
+    let (chat, permissions) = find_group_chat(db, chat_id, actor_id).await?;
+    permissions.require(ChatPermissions::MANAGE_MEMBERS)?;
+
+    let actor_role = role_of(&chat, actor_id).ok_or(ApiError::ChatManagePermissionDenied)?;
+    let user_role = role_of(&chat, user_id).ok_or(ApiError::UserNotFound)?;
+
+    match (actor_role, user_role) {
+        (_, ChatRole::Owner) => return Err(ApiError::ChatManagePermissionDenied),
+        (ChatRole::Owner, _) | (ChatRole::Moderator, ChatRole::Member) => {}
+        _ => return Err(ApiError::ChatManagePermissionDenied),
+    }
+
+    update_group_chat(
+        db,
+        doc! {
+            "_id": chat_id,
+            "ownerId": { "$ne": user_id }
+        },
+        doc! {
+            "$pull": {
+                "recipients": {
+                    "id": user_id
+                }
+            }
+        },
+        None,
+    )
+    .await?
+    .ok_or(ApiError::ChatNotFound)
+}
+
+pub async fn leave_group_chat(
+    db: &Database,
+    chat_id: &str,
+    user_id: &str,
+) -> ApiResult<LeaveGroupResult> {
+    let (chat, _) = find_group_chat(db, chat_id, user_id).await?;
+    role_of(&chat, user_id).ok_or(ApiError::ChatNotFound)?;
+
+    let mut session = db
+        .client
+        .start_session(None)
+        .await
+        .context("leave_group_chat: Failed to start session.")?;
+    let result = session
+        .with_transaction(
+            (
+                &db.chats::<Chat>(),
+                &db.messages::<Document>(),
+                &db.attachments::<Document>(),
+                &chat,
+                user_id,
+            ),
+            |session, (chats, messages, attachments, chat, user_id)| {
+                async move {
+                    leave_group_chat_with_session(
+                        chats,
+                        messages,
+                        attachments,
+                        session,
+                        chat,
+                        user_id,
+                    )
+                    .await
+                }
+                .boxed()
+            },
+            None,
+        )
+        .await
+        .context("leave_group_chat: Failed to execute transaction.")?;
+
+    Ok(result)
+}
+
+/// removes `user_id` from the group as part of the caller's transaction.
+/// the chat is deleted when the last member leaves, ownership moves on when the owner leaves.
+pub async fn leave_group_chat_with_session(
+    chats: &Collection<Chat>,
+    messages: &Collection<Document>,
+    attachments: &Collection<Document>,
+    session: &mut ClientSession,
+    chat: &Chat,
+    user_id: &str,
+) -> mongodb::error::Result<LeaveGroupResult> {
+    let remaining: Vec<&ChatRecipient> =
+        chat.recipients.iter().filter(|r| r.id != user_id).collect();
+
+    if remaining.is_empty() {
+        let attachment_ids: Vec<String> = attachments
+            .find_with_session(
+                doc! { "chatId": &chat.id },
+                FindOptions::builder().projection(doc! { "_id": 1 }).build(),
+                session,
+            )
+            .await?
+            .stream(session)
+            .try_filter_map(|attachment| async move {
+                Ok(attachment.get_str("_id").ok().map(str::to_owned))
+            })
+            .try_collect()
+            .await?;
+
+        chats
+            .delete_one_with_session(doc! { "_id": &chat.id }, None, session)
+            .await?;
+        messages
+            .delete_many_with_session(doc! { "chatId": &chat.id }, None, session)
+            .await?;
+        attachments
+            .delete_many_with_session(doc! { "chatId": &chat.id }, None, session)
+            .await?;
+
+        return Ok(LeaveGroupResult {
+            chat_id: chat.id.to_owned(),
+            new_owner_id: None,
+            deleted: true,
+            attachment_ids,
+        });
+    }
+
+    chats
+        .update_one_with_session(
+            doc! { "_id": &chat.id },
+            doc! { "$pull": { "recipients": { "id": user_id } } },
+            None,
+            session,
+        )
+        .await?;
+
+    if role_of(chat, user_id) != Some(ChatRole::Owner) {
+        return Ok(LeaveGroupResult {
+            chat_id: chat.id.to_owned(),
+            new_owner_id: None,
+            deleted: false,
+            attachment_ids: vec![],
+        });
+    }
+
+    // moderators are preferred over members when picking the next owner.
+    let new_owner_id = remaining
+        .iter()
+        .find(|r| r.role == Some(ChatRole::Moderator))
+        .unwrap_or(&remaining[0])
+        .id
+        .to_owned();
+
+    chats
+        .update_one_with_session(
+            doc! {
+                "_id": &chat.id,
+                "recipients.id": &new_owner_id
+            },
+            doc! {
+                "$set": {
+                    "ownerId": &new_owner_id,
+                    "recipients.$.role": ChatRole::Owner.to_string()
+                }
+            },
+            None,
+            session,
+        )
+        .await?;
+
+    Ok(LeaveGroupResult {
+        chat_id: chat.id.to_owned(),
+        new_owner_id: Some(new_owner_id),
+        deleted: false,
+        attachment_ids: vec![],
+    })
+}
+
+pub async fn transfer_group_ownership(
+    db: &Database,
+    chat_id: &str,
+    owner_id: &str,
+    new_owner_id: &str,
+) -> ApiResult<Chat> {
+    let (chat, permissions) = find_group_chat(db, chat_id, owner_id).await?;
+    permissions.require(ChatPermissions::MANAGE_ROLES)?;
+
+    if role_of(&chat, owner_id) != Some(ChatRole::Owner) {
+        return Err(ApiError::ChatManagePermissionDenied);
+    }
+    if role_of(&chat, new_owner_id).is_none() {
+        return Err(ApiError::UserNotFound);
+    }
+    if owner_id == new_owner_id {
+        return Ok(chat);
+    }
+
+    update_group_chat(
+        db,
+        doc! {
+            "_id": chat_id,
+            "ownerId": owner_id,
+            "recipients.id": new_owner_id
+        },
+        doc! {
+            "$set": {
+                "ownerId": new_owner_id,
+                "recipients.$[newOwner].role": ChatRole::Owner.to_string(),
+                "recipients.$[oldOwner].role": ChatRole::Member.to_string()
+            }
+        },
+        Some(vec![
+            doc! { "newOwner.id": new_owner_id },
+            doc! { "oldOwner.id": owner_id },
+        ]),
+    )
+    .await?
+    .ok_or(ApiError::ChatManagePermissionDenied)
+}
+
+/// changes the role of a group member. ownership can only be changed with `transfer_group_ownership`.
+pub async fn set_group_recipient_role(
+    db: &Database,
+    chat_id: &str,
+    actor_id: &str,
+    user_id: &str,
+    role: ChatRole,
+) -> ApiResult<Chat> {
+    let (chat, permissions) = find_group_chat(db, chat_id, actor_id).await?;
+    permissions.require(ChatPermissions::MANAGE_ROLES)?;
+
+    match role_of(&chat, user_id) {
+        None => return Err(ApiError::UserNotFound),
+        Some(ChatRole::Owner) => return Err(ApiError::ChatManagePermissionDenied),
+        Some(_) => {}
+    }
+    if role == ChatRole::Owner {
+        return Err(ApiError::ChatManagePermissionDenied);
+    }
+
+    update_group_chat(
+        db,
+        doc! {
+            "_id": chat_id,
+            "recipients.id": user_id
+        },
+        doc! {
+            "$set": {
+                "recipients.$.role": role.to_string()
+            }
+        },
+        None,
+    )
+    .await?
+    .ok_or(ApiError::UserNotFound)
+}
+
+/// moves the read marker of `user_id` forward to `message_id`.
+/// returns whether the receipt should be sent to the other recipients. it isn't sent if the marker
+/// was already at or past that message, or if the users can't message each other, e.g. after a block.
+pub async fn mark_chat_read(
+    db: &Database,
+    user_id: &str,
+    chat_id: &str,
+    message_id: &str,
+) -> ApiResult<bool> {
+    let (_, permissions) = find_chat_with_permissions(db, chat_id, user_id).await?;
+    permissions.require(ChatPermissions::READ_MESSAGES)?;
+
+    let message = db
+        .messages::<Document>()
+        .find_one(doc! { "_id": message_id, "chatId": chat_id }, None)
+        .await
+        .context("mark_chat_read: Failed to find message.")?;
+    if message.is_none() {
+        return Err(ApiError::MessageNotFound);
+    }
+
+    let result = db
+        .chats::<Chat>()
+        .update_one(
+            doc! { "_id": chat_id },
+            doc! {
+                "$set": {
+                    "recipients.$[recipient].lastReadId": message_id
+                }
+            },
+            UpdateOptions::builder()
+                .array_filters(vec![doc! {
+                    "recipient.id": user_id,
+                    "recipient.lastReadId": { "$not": { "$gte": message_id } }
+                }])
+                .build(),
+        )
+        .await
+        .context("mark_chat_read: Failed to update chat.")?;
+
+    Ok(result.modified_count > 0 && permissions.contains(ChatPermissions::SEND_MESSAGES))
+}
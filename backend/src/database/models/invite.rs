@@ -0,0 +1,117 @@
+use anyhow::Context;
+use futures_util::TryStreamExt;
+use mongodb::{
+    bson::{doc, DateTime},
+    options::FindOptions,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    database::Database,
+    util::result::{ApiError, ApiResult},
+};
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Invite {
+    /// the invite code.
+    #[serde(rename = "_id")]
+    pub code: String,
+    pub creator_id: String,
+    pub max_uses: i32,
+    pub uses: i32,
+    pub expires_at: DateTime,
+}
+
+pub async fn create_invite(
+    db: &Database,
+    creator_id: &str,
+    max_uses: i32,
+    expires_in_s: i64,
+) -> ApiResult<Invite> {
+    let invite = Invite {
+        code: nanoid::nanoid!(12),
+        creator_id: creator_id.to_string(),
+        max_uses,
+        uses: 0,
+        expires_at: DateTime::from_millis(DateTime::now().timestamp_millis() + expires_in_s * 1000),
+    };
+
+    db.invites::<Invite>()
+        .insert_one(&invite, None)
+        .await
+        .context("create_invite: Failed to insert invite.")?;
+
+    Ok(invite)
+}
+
+/// returns the invites created by `creator_id` that can still be used, newest first.
+pub async fn get_invites_of_user(db: &Database, creator_id: &str) -> ApiResult<Vec<Invite>> {
+    let invites = db
+        .invites::<Invite>()
+        .find(
+            doc! {
+                "creatorId": creator_id,
+                "expiresAt": { "$gt": DateTime::now() },
+                "$expr": { "$lt": ["$uses", "$maxUses"] }
+            },
+            FindOptions::builder()
+                .sort(doc! { "expiresAt": -1 })
+                .build(),
+        )
+        .await
+        .context("get_invites_of_user: Failed to find invites.")?
+        .try_collect::<Vec<_>>()
+        .await
+        .context("get_invites_of_user: Failed to iterate over cursor.")?;
+
+    Ok(invites)
+}
+
+pub async fn delete_invite(db: &Database, creator_id: &str, code: &str) -> ApiResult<()> {
+    let result = db
+        .invites::<Invite>()
+        .delete_one(doc! { "_id": code, "creatorId": creator_id }, None)
+        .await
+        .context("delete_invite: Failed to delete invite.")?;
+
+    if result.deleted_count == 0 {
+        return Err(ApiError::InviteNotFound);
+    }
+    Ok(())
+}
+
+/// counts one use of the invite, fails if it expired or has no uses left.
+pub async fn use_invite(db: &Database, code: &str) -> ApiResult<()> {
+    let result = db
+        .invites::<Invite>()
+        .update_one(
+            doc! {
+                "_id": code,
+                "expiresAt": { "$gt": DateTime::now() },
+                "$expr": { "$lt": ["$uses", "$maxUses"] }
+            },
+            doc! { "$inc": { "uses": 1 } },
+            None,
+        )
+        .await
+        .context("use_invite: Failed to update invite.")?;
+
+    if result.modified_count == 0 {
+        return Err(ApiError::InvalidInviteCode);
+    }
+    Ok(())
+}
+
+/// gives back a use taken by `use_invite`, for when the signup failed afterwards.
+pub async fn release_invite(db: &Database, code: &str) -> ApiResult<()> {
+    db.invites::<Invite>()
+        .update_one(
+            doc! { "_id": code, "uses": { "$gt": 0 } },
+            doc! { "$inc": { "uses": -1 } },
+            None,
+        )
+        .await
+        .context("release_invite: Failed to update invite.")?;
+    Ok(())
+}
@@ -1,17 +1,31 @@
 use crate::{
     database::Database,
-    routes::chat::MessageJson,
-    util::result::{ApiError, ApiResult},
+    routes::chat::{
+        MessageDeleteResponse, MessageJson, MessageReactionResponse, MessageReplyJson,
+        ReactionJson, SearchMessagesQuery,
+    },
+    util::{
+        constants::MAX_REACTIONS_PER_MESSAGE,
+        permissions::{resolve_chat_permissions, ChatPermissions},
+        result::{ApiError, ApiResult},
+    },
 };
-use anyhow::{anyhow, Context};
+use anyhow::Context;
 use futures_util::{FutureExt, TryStreamExt};
-use mongodb::{bson::doc, options::FindOptions};
+use mongodb::{
+    bson::{doc, DateTime, Document},
+    options::{
+        FindOneAndUpdateOptions, FindOneOptions, FindOptions, ReturnDocument, UpdateOptions,
+    },
+};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use ulid::Ulid;
 
 use super::{
-    chat::Chat,
-    user::{self, RelationStatus},
+    attachment::{self, Attachment, MessageAttachment},
+    chat::{self, Chat},
+    user,
 };
 
 #[derive(Serialize, Deserialize)]
@@ -22,10 +36,113 @@ pub struct Message {
     pub chat_id: String,
     pub author_id: String,
     pub content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub edited_at: Option<DateTime>,
+    /// previous versions of the content, oldest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<MessageRevision>,
+    /// set when the message was deleted, the content and history are cleared at the same time.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<DateTime>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reactions: Vec<Reaction>,
+    /// id of the message this one replies to, always in the same chat.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<MessageAttachment>,
 }
 
-pub async fn get_messages_by_id(db: &Database, m_ids: &[&str]) -> ApiResult<Vec<MessageJson>> {
-    let mut messages_cur = db
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Reaction {
+    pub emoji: String,
+    pub user_ids: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageRevision {
+    pub content: String,
+    /// when this version was replaced by a newer one.
+    pub replaced_at: DateTime,
+}
+
+/// max number of characters of the replied message's content included in a reply preview.
+const REPLY_SNIPPET_LENGTH: usize = 100;
+
+fn into_message_json(message: Message, replied_messages: &[Message]) -> ApiResult<MessageJson> {
+    let timestamp = Ulid::from_string(message.id.as_str())
+        .context("into_message_json: invalid ulid found in message id.")?
+        .timestamp_ms();
+
+    Ok(MessageJson {
+        id: message.id,
+        chat_id: message.chat_id,
+        author_id: message.author_id,
+        content: message.content,
+        timestamp,
+        edited_at: message.edited_at.map(|t| t.timestamp_millis() as u64),
+        deleted: message.deleted_at.is_some(),
+        reactions: message
+            .reactions
+            .into_iter()
+            .filter(|r| !r.user_ids.is_empty())
+            .map(|r| ReactionJson {
+                emoji: r.emoji,
+                count: r.user_ids.len(),
+            })
+            .collect(),
+        reply_to: message.reply_to.map(|reply_id| {
+            match replied_messages.iter().find(|m| m.id == reply_id) {
+                Some(replied) => MessageReplyJson {
+                    id: reply_id,
+                    author_id: Some(replied.author_id.to_owned()),
+                    content: replied.content.chars().take(REPLY_SNIPPET_LENGTH).collect(),
+                    deleted: replied.deleted_at.is_some(),
+                },
+                // the replied message is gone, e.g. its chat was deleted.
+                None => MessageReplyJson {
+                    id: reply_id,
+                    author_id: None,
+                    content: String::new(),
+                    deleted: true,
+                },
+            }
+        }),
+        attachments: message.attachments,
+    })
+}
+
+/// converts messages to json, fetching the messages they reply to for the previews.
+async fn into_messages_json(db: &Database, messages: Vec<Message>) -> ApiResult<Vec<MessageJson>> {
+    let reply_ids: Vec<&str> = messages
+        .iter()
+        .filter_map(|m| m.reply_to.as_deref())
+        .collect();
+
+    let replied_messages = if reply_ids.is_empty() {
+        vec![]
+    } else {
+        find_messages_by_id(db, &reply_ids).await?
+    };
+
+    messages
+        .into_iter()
+        .map(|message| into_message_json(message, &replied_messages))
+        .collect()
+}
+
+async fn into_single_message_json(db: &Database, message: Message) -> ApiResult<MessageJson> {
+    into_messages_json(db, vec![message])
+        .await?
+        .pop()
+        .context("into_single_message_json: message went missing during conversion.")
+        .map_err(ApiError::from)
+}
+
+async fn find_messages_by_id(db: &Database, m_ids: &[&str]) -> ApiResult<Vec<Message>> {
+    let messages = db
         .messages::<Message>()
         .find(
             doc! {
@@ -36,28 +153,18 @@ pub async fn get_messages_by_id(db: &Database, m_ids: &[&str]) -> ApiResult<Vec<
             None,
         )
         .await
-        .context("get_messages_by_id: Failed to find messages.")?;
-
-    let mut messages = Vec::new();
-
-    while let Some(message) = messages_cur
-        .try_next()
+        .context("find_messages_by_id: Failed to find messages.")?
+        .try_collect::<Vec<_>>()
         .await
-        .context("get_messages_by_id: Failed to get next message from cursor.")?
-    {
-        let timestamp = Ulid::from_string(message.id.as_str())
-            .context("get_messages_by_id: invalid ulid found while parsing from cursor.")?
-            .timestamp_ms();
-        messages.push(MessageJson {
-            id: message.id,
-            chat_id: message.chat_id,
-            author_id: message.author_id,
-            content: message.content,
-            timestamp,
-        });
-    }
+        .context("find_messages_by_id: Failed to iterate over cursor.")?;
+
     Ok(messages)
 }
+
+pub async fn get_messages_by_id(db: &Database, m_ids: &[&str]) -> ApiResult<Vec<MessageJson>> {
+    let messages = find_messages_by_id(db, m_ids).await?;
+    into_messages_json(db, messages).await
+}
 pub async fn get_messages(
     db: &Database,
     user_id: &str,
@@ -66,25 +173,8 @@ pub async fn get_messages(
     after: &Option<String>,
     limit: i64,
 ) -> ApiResult<Vec<MessageJson>> {
-    let chat = db
-        .chats::<Chat>()
-        .find_one(
-            doc! {
-                "_id": chat_id
-            },
-            None,
-        )
-        .await
-        .context("get_messages: Failed to find chat.")?;
-
-    let chat = match chat {
-        Some(chat) => chat,
-        None => return Err(ApiError::ChatNotFound),
-    };
-
-    if !chat.recipients.iter().any(|r| r.id == user_id) {
-        return Err(ApiError::ChatReadPermissionDenied);
-    }
+    let (_, permissions) = chat::find_chat_with_permissions(db, chat_id, user_id).await?;
+    permissions.require(ChatPermissions::READ_MESSAGES)?;
 
     let mut query = doc! {
         "chatId": chat_id
@@ -127,67 +217,189 @@ pub async fn get_messages(
         .await
         .context("get_messages: Failed to get next message from cursor.")?
     {
-        let timestamp = Ulid::from_string(message.id.as_str())
-            .context("get_messages: invalid ulid found while parsing from cursor.")?
-            .timestamp_ms();
-        messages.push(MessageJson {
-            id: message.id,
-            chat_id: message.chat_id,
-            author_id: message.author_id,
-            content: message.content,
-            timestamp,
-        });
+        messages.push(message);
     }
 
-    Ok(messages)
+    into_messages_json(db, messages).await
 }
 
-pub async fn save_direct_message(
+/// counts the messages in each chat that `user_id` hasn't read yet, their own and deleted messages are not counted.
+/// chats without unread messages are left out.
+pub async fn get_unread_counts(
     db: &Database,
-    author_id: &str,
-    chat_id: &str,
-    content: &str,
-) -> ApiResult<MessageJson> {
-    let chat = db
-        .chats::<Chat>()
-        .find_one(
-            doc! {
-                "_id": chat_id
-            },
+    user_id: &str,
+    chats: &[&Chat],
+) -> ApiResult<HashMap<String, u64>> {
+    let conditions: Vec<Document> = chats
+        .iter()
+        .filter_map(|chat| {
+            let last_message_id = chat.last_message_id.as_ref()?;
+            let last_read_id = chat
+                .recipients
+                .iter()
+                .find(|r| r.id == user_id)
+                .and_then(|r| r.last_read_id.as_ref());
+
+            match last_read_id {
+                Some(last_read_id) if last_read_id >= last_message_id => None,
+                Some(last_read_id) => Some(doc! {
+                    "chatId": &chat.id,
+                    "_id": { "$gt": last_read_id }
+                }),
+                None => Some(doc! { "chatId": &chat.id }),
+            }
+        })
+        .collect();
+
+    let mut counts = HashMap::new();
+    if conditions.is_empty() {
+        return Ok(counts);
+    }
+
+    let mut cursor = db
+        .messages::<Message>()
+        .aggregate(
+            [
+                doc! {
+                    "$match": {
+                        "$or": conditions,
+                        "authorId": { "$ne": user_id },
+                        "deletedAt": { "$exists": false }
+                    }
+                },
+                doc! {
+                    "$group": {
+                        "_id": "$chatId",
+                        "count": { "$sum": 1 }
+                    }
+                },
+            ],
             None,
         )
         .await
-        .context("save_direct_message: Failed to find chat.")?;
+        .context("get_unread_counts: Failed to aggregate messages.")?;
+
+    while let Some(result) = cursor
+        .try_next()
+        .await
+        .context("get_unread_counts: Failed to get next result from cursor.")?
+    {
+        let chat_id = result
+            .get_str("_id")
+            .context("get_unread_counts: Missing chat id.")?;
+        let count = result
+            .get_i32("count")
+            .context("get_unread_counts: Missing count.")?;
+        counts.insert(chat_id.to_string(), count as u64);
+    }
+
+    Ok(counts)
+}
+
+/// full-text search over the messages of one chat, or of every chat the user can read.
+/// results are sorted newest first and paginated with `before`.
+pub async fn search_messages(
+    db: &Database,
+    user_id: &str,
+    chat_id: Option<&str>,
+    search: &SearchMessagesQuery,
+) -> ApiResult<Vec<MessageJson>> {
+    let chat_ids: Vec<String> = match chat_id {
+        Some(chat_id) => {
+            let (_, permissions) = chat::find_chat_with_permissions(db, chat_id, user_id).await?;
+            permissions.require(ChatPermissions::READ_MESSAGES)?;
+            vec![chat_id.to_string()]
+        }
+        None => {
+            let chats = chat::get_chats_of_user(db, user_id).await?;
+            let relations = user::find_relations_of_user(db, user_id).await?;
+            chats
+                .into_iter()
+                .filter(|chat| {
+                    resolve_chat_permissions(chat, user_id, &relations)
+                        .contains(ChatPermissions::READ_MESSAGES)
+                })
+                .map(|chat| chat.id)
+                .collect()
+        }
+    };
+    if chat_ids.is_empty() {
+        return Ok(vec![]);
+    }
 
-    let chat = match chat {
-        Some(chat) => chat,
-        None => return Err(ApiError::ChatNotFound),
+    let mut query = doc! {
+        "$text": { "$search": &search.query },
+        "chatId": { "$in": chat_ids },
+        "deletedAt": { "$exists": false }
     };
-    if chat.recipients.len() != 2 {
-        return Err(ApiError::ChatWritePermissionDenied);
+    if let Some(ref author_id) = search.author_id {
+        query.insert("authorId", author_id);
     }
 
-    // TODO: changed r.id == *author_id to r.id == author_id. check on this if it has the same effect.
-    chat.recipients
-        .iter()
-        .find(|r| r.id == author_id)
-        .ok_or(ApiError::ChatWritePermissionDenied)?;
-    let other_user_id = &chat
-        .recipients
-        .iter()
-        .find(|r| r.id != author_id)
-        .ok_or(ApiError::UnknownError(anyhow!(
-            "save_direct_message: No other user found in chat, only the authors id exists."
-        )))?
-        .id;
-    let user_relations = user::find_relations_of_user(db, author_id).await?;
-
-    let relation_to_user = user_relations
-        .iter()
-        .find(|r| r.id == *other_user_id)
-        .ok_or(ApiError::ChatWritePermissionDenied)?;
-    if relation_to_user.status != RelationStatus::Friend {
-        return Err(ApiError::ChatWritePermissionDenied);
+    // message ids are ulids, so a date range is a range of ids.
+    let mut id_range = doc! {};
+    if let Some(since) = search.since {
+        id_range.insert("$gte", Ulid::from_parts(since, 0).to_string());
+    }
+    if let Some(until) = search.until {
+        id_range.insert("$lte", Ulid::from_parts(until, u128::MAX).to_string());
+    }
+    if let Some(ref before) = search.before {
+        id_range.insert("$lt", before);
+    }
+    if !id_range.is_empty() {
+        query.insert("_id", id_range);
+    }
+
+    let messages = db
+        .messages::<Message>()
+        .find(
+            query,
+            FindOptions::builder()
+                .sort(doc! {
+                    "_id": -1
+                })
+                .limit(search.limit.unwrap_or(25))
+                .build(),
+        )
+        .await
+        .context("search_messages: Failed to find messages.")?
+        .try_collect::<Vec<_>>()
+        .await
+        .context("search_messages: Failed to iterate over cursor.")?;
+
+    into_messages_json(db, messages).await
+}
+
+pub async fn save_message(
+    db: &Database,
+    author_id: &str,
+    chat_id: &str,
+    content: &str,
+    reply_to: &Option<String>,
+    attachment_ids: &[String],
+) -> ApiResult<MessageJson> {
+    let (_, permissions) = chat::find_chat_with_permissions(db, chat_id, author_id).await?;
+    permissions.require(ChatPermissions::SEND_MESSAGES)?;
+
+    if let Some(reply_id) = reply_to {
+        match find_message(db, chat_id, reply_id).await {
+            Ok(replied) if replied.deleted_at.is_none() => {}
+            Ok(_) | Err(ApiError::MessageNotFound) => return Err(ApiError::InvalidReply),
+            Err(err) => return Err(err),
+        }
+    }
+
+    let mut attachment_ids = attachment_ids.to_vec();
+    attachment_ids.sort();
+    attachment_ids.dedup();
+    let attachments = if attachment_ids.is_empty() {
+        vec![]
+    } else {
+        attachment::find_unsent_attachments(db, author_id, chat_id, &attachment_ids).await?
+    };
+    if attachments.len() != attachment_ids.len() {
+        return Err(ApiError::InvalidAttachment);
     }
 
     let mid = Ulid::new();
@@ -196,21 +408,57 @@ pub async fn save_direct_message(
         chat_id: chat_id.to_string(),
         author_id: author_id.to_string(),
         content: content.trim().to_string(),
+        edited_at: None,
+        history: vec![],
+        deleted_at: None,
+        reactions: vec![],
+        reply_to: reply_to.to_owned(),
+        attachments: attachments
+            .into_iter()
+            .map(MessageAttachment::from)
+            .collect(),
     };
 
     let mut session = db
         .client
         .start_session(None)
         .await
-        .context("save_direct_message: Failed to start session.")?;
+        .context("save_message: Failed to start session.")?;
     session
         .with_transaction(
-            (&db.chats::<Chat>(), &db.messages::<Message>(), &message),
-            |session, (chats, messages, msg)| {
+            (
+                &db.chats::<Chat>(),
+                &db.messages::<Message>(),
+                &db.attachments::<Attachment>(),
+                &message,
+                &attachment_ids,
+            ),
+            |session, (chats, messages, attachments, msg, attachment_ids)| {
                 async move {
                     messages
                         .insert_one_with_session(*msg, None, session)
                         .await?;
+                    if !attachment_ids.is_empty() {
+                        let result = attachments
+                            .update_many_with_session(
+                                doc! {
+                                    "_id": { "$in": *attachment_ids },
+                                    "messageId": { "$exists": false }
+                                },
+                                doc! {
+                                    "$set": {
+                                        "messageId": &msg.id
+                                    }
+                                },
+                                None,
+                                session,
+                            )
+                            .await?;
+                        // another message claimed one of the attachments first.
+                        if result.modified_count != attachment_ids.len() as u64 {
+                            return Err(mongodb::error::Error::custom(ApiError::InvalidAttachment));
+                        }
+                    }
                     chats
                         .update_one_with_session(
                             doc! {
@@ -218,10 +466,13 @@ pub async fn save_direct_message(
                             },
                             doc! {
                                 "$set": {
-                                    "lastMessageId": &msg.id
+                                    "lastMessageId": &msg.id,
+                                    "recipients.$[author].lastReadId": &msg.id
                                 }
                             },
-                            None,
+                            UpdateOptions::builder()
+                                .array_filters(vec![doc! { "author.id": &author_id }])
+                                .build(),
                             session,
                         )
                         .await?;
@@ -233,12 +484,315 @@ pub async fn save_direct_message(
             None,
         )
         .await
-        .context("save_direct_message: Failed to execute transaction.")?;
-    Ok(MessageJson {
+        .map_err(|err| match err.get_custom::<ApiError>() {
+            Some(ApiError::InvalidAttachment) => ApiError::InvalidAttachment,
+            _ => anyhow::Error::from(err)
+                .context("save_message: Failed to execute transaction.")
+                .into(),
+        })?;
+    into_single_message_json(db, message).await
+}
+
+async fn find_message(db: &Database, chat_id: &str, message_id: &str) -> ApiResult<Message> {
+    let message = db
+        .messages::<Message>()
+        .find_one(
+            doc! {
+                "_id": message_id,
+                "chatId": chat_id
+            },
+            None,
+        )
+        .await
+        .context("find_message: Failed to find message.")?;
+
+    message.ok_or(ApiError::MessageNotFound)
+}
+
+/// replaces the content of a message, the old content is kept in the message's history.
+pub async fn edit_message(
+    db: &Database,
+    author_id: &str,
+    chat_id: &str,
+    message_id: &str,
+    content: &str,
+) -> ApiResult<MessageJson> {
+    let (_, permissions) = chat::find_chat_with_permissions(db, chat_id, author_id).await?;
+    permissions.require(ChatPermissions::SEND_MESSAGES)?;
+
+    let message = find_message(db, chat_id, message_id).await?;
+    if message.deleted_at.is_some() {
+        return Err(ApiError::MessageNotFound);
+    }
+    if message.author_id != author_id {
+        return Err(ApiError::NotMessageAuthor);
+    }
+
+    let content = content.trim();
+    if message.content == content {
+        return into_single_message_json(db, message).await;
+    }
+
+    let now = DateTime::now();
+    let message = db
+        .messages::<Message>()
+        .find_one_and_update(
+            doc! {
+                "_id": message_id,
+                "chatId": chat_id,
+                "authorId": author_id,
+                "deletedAt": { "$exists": false }
+            },
+            doc! {
+                "$set": {
+                    "content": content,
+                    "editedAt": now
+                },
+                "$push": {
+                    "history": {
+                        "content": &message.content,
+                        "replacedAt": now
+                    }
+                }
+            },
+            FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .build(),
+        )
+        .await
+        .context("edit_message: Failed to update message.")?
+        .ok_or(ApiError::MessageNotFound)?;
+
+    into_single_message_json(db, message).await
+}
+
+/// turns a message into a tombstone. authors can always delete their own messages,
+/// deleting someone else's message requires `DELETE_MESSAGES`.
+pub async fn delete_message(
+    db: &Database,
+    user_id: &str,
+    chat_id: &str,
+    message_id: &str,
+) -> ApiResult<MessageDeleteResponse> {
+    let (_, permissions) = chat::find_chat_with_permissions(db, chat_id, user_id).await?;
+
+    let message = find_message(db, chat_id, message_id).await?;
+    if message.deleted_at.is_some() {
+        return Err(ApiError::MessageNotFound);
+    }
+    if message.author_id == user_id {
+        permissions.require(ChatPermissions::READ_MESSAGES)?;
+    } else {
+        permissions.require(ChatPermissions::DELETE_MESSAGES)?;
+    }
+
+    let mut session = db
+        .client
+        .start_session(None)
+        .await
+        .context("delete_message: Failed to start session.")?;
+    let last_message_id = session
+        .with_transaction(
+            (
+                &db.chats::<Chat>(),
+                &db.messages::<Message>(),
+                &db.attachments::<Attachment>(),
+                chat_id,
+                message_id,
+            ),
+            |session, (chats, messages, attachments, chat_id, message_id)| {
+                async move {
+                    messages
+                        .update_one_with_session(
+                            doc! {
+                                "_id": *message_id,
+                                "chatId": *chat_id
+                            },
+                            doc! {
+                                "$set": {
+                                    "content": "",
+                                    "deletedAt": DateTime::now()
+                                },
+                                "$unset": {
+                                    "history": "",
+                                    "editedAt": "",
+                                    "reactions": "",
+                                    "attachments": ""
+                                }
+                            },
+                            None,
+                            session,
+                        )
+                        .await?;
+                    attachments
+                        .delete_many_with_session(doc! { "messageId": *message_id }, None, session)
+                        .await?;
+
+                    let chat = chats
+                        .find_one_with_session(doc! { "_id": *chat_id }, None, session)
+                        .await?;
+                    let last_message_id = chat.and_then(|chat| chat.last_message_id);
+                    if last_message_id.as_deref() != Some(*message_id) {
+                        return Ok(last_message_id);
+                    }
+
+                    let last_message = messages
+                        .find_one_with_session(
+                            doc! {
+                                "chatId": *chat_id,
+                                "deletedAt": { "$exists": false }
+                            },
+                            FindOneOptions::builder().sort(doc! { "_id": -1 }).build(),
+                            session,
+                        )
+                        .await?;
+
+                    let update = match last_message {
+                        Some(ref message) => doc! { "$set": { "lastMessageId": &message.id } },
+                        None => doc! { "$unset": { "lastMessageId": "" } },
+                    };
+                    chats
+                        .update_one_with_session(doc! { "_id": *chat_id }, update, None, session)
+                        .await?;
+
+                    Ok(last_message.map(|message| message.id))
+                }
+                .boxed()
+            },
+            None,
+        )
+        .await
+        .context("delete_message: Failed to execute transaction.")?;
+
+    Ok(MessageDeleteResponse {
         id: message.id,
         chat_id: message.chat_id,
-        author_id: message.author_id,
-        content: message.content,
-        timestamp: mid.timestamp_ms(),
+        last_message_id,
+        attachment_ids: message.attachments.into_iter().map(|a| a.id).collect(),
+    })
+}
+
+async fn update_message(
+    db: &Database,
+    filter: Document,
+    update: Document,
+) -> ApiResult<Option<Message>> {
+    let message = db
+        .messages::<Message>()
+        .find_one_and_update(
+            filter,
+            update,
+            FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .build(),
+        )
+        .await
+        .context("update_message: Failed to update message.")?;
+
+    Ok(message)
+}
+
+/// adds or removes the reaction of `user_id` on a message.
+pub async fn set_reaction(
+    db: &Database,
+    user_id: &str,
+    chat_id: &str,
+    message_id: &str,
+    emoji: &str,
+    added: bool,
+) -> ApiResult<MessageReactionResponse> {
+    let (_, permissions) = chat::find_chat_with_permissions(db, chat_id, user_id).await?;
+    permissions.require(ChatPermissions::READ_MESSAGES)?;
+
+    let message_filter = doc! {
+        "_id": message_id,
+        "chatId": chat_id,
+        "deletedAt": { "$exists": false }
+    };
+
+    let mut with_emoji = message_filter.clone();
+    with_emoji.insert("reactions.emoji", emoji);
+
+    let message = if added {
+        let mut without_emoji = message_filter.clone();
+        without_emoji.insert("reactions.emoji", doc! { "$ne": emoji });
+        // a new emoji can only be added while the message has fewer than the maximum.
+        without_emoji.insert(
+            format!("reactions.{}", MAX_REACTIONS_PER_MESSAGE - 1),
+            doc! { "$exists": false },
+        );
+
+        let mut message = None;
+        // tried twice in case someone else added the same emoji in the meantime.
+        for _ in 0..2 {
+            message = update_message(
+                db,
+                with_emoji.clone(),
+                doc! { "$addToSet": { "reactions.$.userIds": user_id } },
+            )
+            .await?;
+            if message.is_some() {
+                break;
+            }
+            message = update_message(
+                db,
+                without_emoji.clone(),
+                doc! { "$push": { "reactions": { "emoji": emoji, "userIds": [user_id] } } },
+            )
+            .await?;
+            if message.is_some() {
+                break;
+            }
+        }
+        if message.is_none() {
+            let existing = db
+                .messages::<Message>()
+                .find_one(message_filter, None)
+                .await
+                .context("set_reaction: Failed to find message.")?;
+            if existing.is_some_and(|m| m.reactions.len() >= MAX_REACTIONS_PER_MESSAGE) {
+                return Err(ApiError::TooManyReactions);
+            }
+        }
+        message
+    } else {
+        let message = update_message(
+            db,
+            with_emoji,
+            doc! { "$pull": { "reactions.$.userIds": user_id } },
+        )
+        .await?;
+        match message {
+            Some(_) => {
+                update_message(
+                    db,
+                    message_filter,
+                    doc! { "$pull": { "reactions": { "userIds": { "$size": 0 } } } },
+                )
+                .await?
+            }
+            // nothing to remove, the reaction didn't exist.
+            None => db
+                .messages::<Message>()
+                .find_one(message_filter, None)
+                .await
+                .context("set_reaction: Failed to find message.")?,
+        }
+    };
+    let message = message.ok_or(ApiError::MessageNotFound)?;
+
+    let count = message
+        .reactions
+        .iter()
+        .find(|r| r.emoji == emoji)
+        .map_or(0, |r| r.user_ids.len());
+
+    Ok(MessageReactionResponse {
+        chat_id: message.chat_id,
+        message_id: message.id,
+        user_id: user_id.to_string(),
+        emoji: emoji.to_string(),
+        added,
+        count,
     })
 }
@@ -0,0 +1,472 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use mongodb::{
+    bson::{doc, DateTime},
+    options::{FindOneAndUpdateOptions, ReturnDocument},
+};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::{Deserialize, Serialize};
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::{
+    database::Database,
+    util::{
+        config::ApiConfig,
+        constants::{
+            MFA_CHALLENGE_LIFETIME_MS, MFA_CHALLENGE_MAX_ATTEMPTS, MFA_RECOVERY_CODE_COUNT,
+            TOTP_ISSUER,
+        },
+        password::verify_password,
+        result::{ApiError, ApiResult},
+    },
+};
+
+use super::{
+    session::hash_token,
+    user::{self, UserAccount},
+};
+
+const TOTP_DIGITS: usize = 6;
+const TOTP_STEP: u64 = 30;
+const RECOVERY_CODE_ALPHABET: [char; 32] = [
+    'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'j', 'k', 'm', 'n', 'p', 'q', 'r', 's', 't', 'u', 'v',
+    'w', 'x', 'y', 'z', '2', '3', '4', '5', '6', '7', '8', '9', '0',
+];
+
+/// two-factor authentication state, stored in the user document.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct UserMfa {
+    /// base32 encoded totp secret.
+    pub totp_secret: String,
+    /// false until the user confirms the enrollment with a valid code.
+    pub enabled: bool,
+    /// keyed hashes of the unused recovery codes.
+    #[serde(default)]
+    pub recovery_codes: Vec<String>,
+    /// the last totp time step that was accepted, codes can't be reused.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_used_step: Option<i64>,
+}
+
+/// a login that passed the password check and still needs a second factor.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MfaChallenge {
+    /// keyed hash of the ticket given to the client.
+    #[serde(rename = "_id")]
+    pub ticket_hash: String,
+    pub user_id: String,
+    /// friendly name of the session that will be created.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub attempts: i32,
+    pub expires_at: DateTime,
+}
+
+pub struct TotpEnrollment {
+    pub secret: String,
+    pub uri: String,
+}
+
+fn totp(secret: &str) -> ApiResult<TOTP> {
+    let secret = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|err| anyhow::anyhow!("totp: Invalid totp secret: {err:?}"))?;
+    Ok(TOTP::new_unchecked(
+        Algorithm::SHA1,
+        TOTP_DIGITS,
+        1,
+        TOTP_STEP,
+        secret,
+    ))
+}
+
+fn current_step() -> i64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("TIME TRAVEL>!!?!?!")
+        .as_secs();
+    (now / TOTP_STEP) as i64
+}
+
+/// returns the time step `code` is valid for, allowing one step of clock drift.
+fn find_totp_step(secret: &str, code: &str) -> ApiResult<Option<i64>> {
+    find_totp_step_at(secret, code, current_step())
+}
+
+fn find_totp_step_at(secret: &str, code: &str, step: i64) -> ApiResult<Option<i64>> {
+    let totp = totp(secret)?;
+    Ok((step - 1..=step + 1).find(|step| totp.generate(*step as u64 * TOTP_STEP) == code))
+}
+
+/// a code is only accepted once, and not after a code of a later step was used.
+fn is_unused_step(last_used_step: Option<i64>, step: i64) -> bool {
+    last_used_step.is_none_or(|last_used_step| step > last_used_step)
+}
+
+fn totp_uri(secret: &str, username: &str) -> String {
+    let issuer = utf8_percent_encode(TOTP_ISSUER, NON_ALPHANUMERIC);
+    let username = utf8_percent_encode(username, NON_ALPHANUMERIC);
+    format!(
+        "otpauth://totp/{issuer}:{username}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={TOTP_DIGITS}&period={TOTP_STEP}"
+    )
+}
+
+fn normalize_recovery_code(code: &str) -> String {
+    code.replace('-', "").to_ascii_lowercase()
+}
+
+fn generate_recovery_codes() -> Vec<String> {
+    (0..MFA_RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code = nanoid::nanoid!(10, &RECOVERY_CODE_ALPHABET);
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect()
+}
+
+async fn find_account(db: &Database, user_id: &str) -> ApiResult<UserAccount> {
+    user::find_account_by_id(db, user_id)
+        .await?
+        .ok_or(ApiError::UserNotFound)
+}
+
+/// like `find_account`, but fails with `IncorrectPassword` unless `password` is the user's password.
+async fn find_account_with_password(
+    db: &Database,
+    config: &ApiConfig,
+    user_id: &str,
+    password: &str,
+) -> ApiResult<UserAccount> {
+    let account = find_account(db, user_id).await?;
+    if !verify_password(config, password, &account.password_hash)? {
+        return Err(ApiError::IncorrectPassword);
+    }
+    Ok(account)
+}
+
+/// generates a new secret for `user_id`. it has to be confirmed with `confirm_totp` before it's used.
+pub async fn begin_totp_enrollment(
+    db: &Database,
+    config: &ApiConfig,
+    user_id: &str,
+    password: &str,
+) -> ApiResult<TotpEnrollment> {
+    let account = find_account_with_password(db, config, user_id, password).await?;
+    if account.mfa.as_ref().is_some_and(|mfa| mfa.enabled) {
+        return Err(ApiError::MfaAlreadyEnabled);
+    }
+
+    let secret = match Secret::generate_secret().to_encoded() {
+        Secret::Encoded(secret) => secret,
+        Secret::Raw(_) => unreachable!(),
+    };
+    let mfa = UserMfa {
+        totp_secret: secret.clone(),
+        enabled: false,
+        recovery_codes: vec![],
+        last_used_step: None,
+    };
+
+    db.users::<UserAccount>()
+        .update_one(
+            doc! { "_id": user_id, "mfa.enabled": { "$ne": true } },
+            doc! {
+                "$set": {
+                    "mfa": mongodb::bson::to_bson(&mfa)
+                        .context("begin_totp_enrollment: Failed to serialize mfa.")?
+                }
+            },
+            None,
+        )
+        .await
+        .context("begin_totp_enrollment: Failed to update user.")?;
+
+    Ok(TotpEnrollment {
+        uri: totp_uri(&secret, &account.username),
+        secret,
+    })
+}
+
+/// enables totp if `code` matches the pending secret. returns the recovery codes.
+pub async fn confirm_totp_enrollment(
+    db: &Database,
+    config: &ApiConfig,
+    user_id: &str,
+    password: &str,
+    code: &str,
+) -> ApiResult<Vec<String>> {
+    let account = find_account_with_password(db, config, user_id, password).await?;
+    let mfa = account.mfa.ok_or(ApiError::MfaNotEnabled)?;
+    if mfa.enabled {
+        return Err(ApiError::MfaAlreadyEnabled);
+    }
+
+    let step = find_totp_step(&mfa.totp_secret, code)?.ok_or(ApiError::InvalidMfaCode)?;
+    let recovery_codes = generate_recovery_codes();
+    let recovery_code_hashes: Vec<String> = recovery_codes
+        .iter()
+        .map(|code| hash_token(config, &normalize_recovery_code(code)))
+        .collect();
+
+    let result = db
+        .users::<UserAccount>()
+        .update_one(
+            doc! {
+                "_id": user_id,
+                "mfa.enabled": false,
+                "mfa.totpSecret": &mfa.totp_secret
+            },
+            doc! {
+                "$set": {
+                    "mfa.enabled": true,
+                    "mfa.recoveryCodes": recovery_code_hashes,
+                    "mfa.lastUsedStep": step
+                }
+            },
+            None,
+        )
+        .await
+        .context("confirm_totp_enrollment: Failed to update user.")?;
+
+    if result.modified_count == 0 {
+        return Err(ApiError::InvalidMfaCode);
+    }
+    Ok(recovery_codes)
+}
+
+/// checks a totp or recovery code of `user_id`. used recovery codes and time steps can't be used again.
+pub async fn verify_mfa_code(
+    db: &Database,
+    config: &ApiConfig,
+    user_id: &str,
+    code: &str,
+) -> ApiResult<()> {
+    let account = find_account(db, user_id).await?;
+    let mfa = match account.mfa {
+        Some(mfa) if mfa.enabled => mfa,
+        _ => return Err(ApiError::MfaNotEnabled),
+    };
+
+    let result = if let Some(step) = find_totp_step(&mfa.totp_secret, code)? {
+        if !is_unused_step(mfa.last_used_step, step) {
+            return Err(ApiError::InvalidMfaCode);
+        }
+        // checked again in the filter in case the same code is sent twice at once.
+        db.users::<UserAccount>()
+            .update_one(
+                doc! {
+                    "_id": user_id,
+                    "mfa.lastUsedStep": { "$not": { "$gte": step } }
+                },
+                doc! { "$set": { "mfa.lastUsedStep": step } },
+                None,
+            )
+            .await
+            .context("verify_mfa_code: Failed to update last used step.")?
+    } else {
+        let code_hash = hash_token(config, &normalize_recovery_code(code));
+        db.users::<UserAccount>()
+            .update_one(
+                doc! { "_id": user_id, "mfa.recoveryCodes": &code_hash },
+                doc! { "$pull": { "mfa.recoveryCodes": &code_hash } },
+                None,
+            )
+            .await
+            .context("verify_mfa_code: Failed to remove recovery code.")?
+    };
+
+    if result.modified_count == 0 {
+        return Err(ApiError::InvalidMfaCode);
+    }
+    Ok(())
+}
+
+/// turns off two-factor authentication, a valid code is required.
+pub async fn disable_mfa(
+    db: &Database,
+    config: &ApiConfig,
+    user_id: &str,
+    code: &str,
+) -> ApiResult<()> {
+    verify_mfa_code(db, config, user_id, code).await?;
+
+    db.users::<UserAccount>()
+        .update_one(
+            doc! { "_id": user_id },
+            doc! { "$unset": { "mfa": "" } },
+            None,
+        )
+        .await
+        .context("disable_mfa: Failed to update user.")?;
+    Ok(())
+}
+
+/// replaces the recovery codes of `user_id`, a valid code is required.
+pub async fn regenerate_recovery_codes(
+    db: &Database,
+    config: &ApiConfig,
+    user_id: &str,
+    code: &str,
+) -> ApiResult<Vec<String>> {
+    verify_mfa_code(db, config, user_id, code).await?;
+
+    let recovery_codes = generate_recovery_codes();
+    let recovery_code_hashes: Vec<String> = recovery_codes
+        .iter()
+        .map(|code| hash_token(config, &normalize_recovery_code(code)))
+        .collect();
+
+    db.users::<UserAccount>()
+        .update_one(
+            doc! { "_id": user_id },
+            doc! { "$set": { "mfa.recoveryCodes": recovery_code_hashes } },
+            None,
+        )
+        .await
+        .context("regenerate_recovery_codes: Failed to update user.")?;
+
+    Ok(recovery_codes)
+}
+
+/// creates a short-lived ticket that can be exchanged for a session with a valid code.
+pub async fn create_mfa_challenge(
+    db: &Database,
+    config: &ApiConfig,
+    user_id: &str,
+    name: Option<String>,
+) -> ApiResult<String> {
+    let ticket = nanoid::nanoid!(50);
+    let challenge = MfaChallenge {
+        ticket_hash: hash_token(config, &ticket),
+        user_id: user_id.to_string(),
+        name,
+        attempts: 0,
+        expires_at: DateTime::from_millis(
+            DateTime::now().timestamp_millis() + MFA_CHALLENGE_LIFETIME_MS,
+        ),
+    };
+
+    db.mfa_challenges::<MfaChallenge>()
+        .insert_one(&challenge, None)
+        .await
+        .context("create_mfa_challenge: Failed to insert challenge.")?;
+
+    Ok(ticket)
+}
+
+/// finds the challenge of `ticket` if it can still be completed.
+pub async fn find_mfa_challenge(
+    db: &Database,
+    config: &ApiConfig,
+    ticket: &str,
+) -> ApiResult<MfaChallenge> {
+    db.mfa_challenges::<MfaChallenge>()
+        .find_one(
+            doc! {
+                "_id": hash_token(config, ticket),
+                "attempts": { "$lt": MFA_CHALLENGE_MAX_ATTEMPTS },
+                "expiresAt": { "$gt": DateTime::now() }
+            },
+            None,
+        )
+        .await
+        .context("find_mfa_challenge: Failed to find challenge.")?
+        .ok_or(ApiError::InvalidMfaTicket)
+}
+
+/// checks the code for the challenge. the challenge is deleted once it succeeds or runs out of attempts.
+pub async fn complete_mfa_challenge(
+    db: &Database,
+    config: &ApiConfig,
+    ticket: &str,
+    code: &str,
+) -> ApiResult<MfaChallenge> {
+    let ticket_hash = hash_token(config, ticket);
+    let challenge = db
+        .mfa_challenges::<MfaChallenge>()
+        .find_one_and_update(
+            doc! {
+                "_id": &ticket_hash,
+                "attempts": { "$lt": MFA_CHALLENGE_MAX_ATTEMPTS },
+                "expiresAt": { "$gt": DateTime::now() }
+            },
+            doc! { "$inc": { "attempts": 1 } },
+            FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .build(),
+        )
+        .await
+        .context("complete_mfa_challenge: Failed to find challenge.")?
+        .ok_or(ApiError::InvalidMfaTicket)?;
+
+    let result = verify_mfa_code(db, config, &challenge.user_id, code).await;
+    if result.is_ok() || challenge.attempts >= MFA_CHALLENGE_MAX_ATTEMPTS {
+        db.mfa_challenges::<MfaChallenge>()
+            .delete_one(doc! { "_id": &ticket_hash }, None)
+            .await
+            .context("complete_mfa_challenge: Failed to delete challenge.")?;
+    }
+    result?;
+
+    Ok(challenge)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "JBSWY3DPEHPK3PXPJBSWY3DPEHPK3PXP";
+    const STEP: i64 = 56_000_000;
+
+    fn code_at(step: i64) -> String {
+        totp(SECRET).unwrap().generate(step as u64 * TOTP_STEP)
+    }
+
+    #[test]
+    fn totp_accepts_one_step_of_drift() {
+        for step in STEP - 1..=STEP + 1 {
+            assert_eq!(
+                find_totp_step_at(SECRET, &code_at(step), STEP).unwrap(),
+                Some(step)
+            );
+        }
+        for step in [STEP - 2, STEP + 2] {
+            assert_eq!(
+                find_totp_step_at(SECRET, &code_at(step), STEP).unwrap(),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn used_steps_are_rejected() {
+        assert!(is_unused_step(None, STEP));
+        assert!(is_unused_step(Some(STEP - 1), STEP));
+        assert!(!is_unused_step(Some(STEP), STEP));
+        assert!(!is_unused_step(Some(STEP + 1), STEP));
+    }
+
+    #[test]
+    fn recovery_codes_are_normalized() {
+        assert_eq!(normalize_recovery_code("ABCDE-FGH23"), "abcdefgh23");
+        assert_eq!(normalize_recovery_code("abcdefgh23"), "abcdefgh23");
+        for code in generate_recovery_codes() {
+            assert_eq!(
+                normalize_recovery_code(&code.to_uppercase()),
+                code.replace('-', "")
+            );
+        }
+    }
+
+    #[test]
+    fn totp_uri_encodes_label() {
+        let uri = totp_uri(SECRET, "jane doe:admin");
+        assert!(uri.starts_with(&format!(
+            "otpauth://totp/{}:jane%20doe%3Aadmin?secret={SECRET}&",
+            utf8_percent_encode(TOTP_ISSUER, NON_ALPHANUMERIC)
+        )));
+    }
+}
@@ -1,4 +1,8 @@
+pub mod attachment;
 pub mod chat;
+pub mod invite;
 pub mod message;
+pub mod mfa;
+pub mod password_reset;
 pub mod session;
 pub mod user;
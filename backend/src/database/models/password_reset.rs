@@ -0,0 +1,73 @@
+use anyhow::Context;
+use mongodb::bson::{doc, DateTime};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    database::Database,
+    util::{
+        config::ApiConfig,
+        constants::PASSWORD_RESET_TOKEN_LIFETIME_MS,
+        result::{ApiError, ApiResult},
+    },
+};
+
+use super::session::hash_token;
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PasswordReset {
+    /// keyed hash of the token, same as session tokens.
+    #[serde(rename = "_id")]
+    pub token_hash: String,
+    pub user_id: String,
+    pub expires_at: DateTime,
+}
+
+/// creates a single-use reset token for `user_id`. older tokens of the user are invalidated.
+pub async fn create_password_reset(
+    db: &Database,
+    config: &ApiConfig,
+    user_id: &str,
+) -> ApiResult<String> {
+    let token = nanoid::nanoid!(50);
+    let reset = PasswordReset {
+        token_hash: hash_token(config, &token),
+        user_id: user_id.to_string(),
+        expires_at: DateTime::from_millis(
+            DateTime::now().timestamp_millis() + PASSWORD_RESET_TOKEN_LIFETIME_MS,
+        ),
+    };
+
+    db.password_resets::<PasswordReset>()
+        .delete_many(doc! { "userId": user_id }, None)
+        .await
+        .context("create_password_reset: Failed to delete old reset tokens.")?;
+    db.password_resets::<PasswordReset>()
+        .insert_one(&reset, None)
+        .await
+        .context("create_password_reset: Failed to insert reset token.")?;
+
+    Ok(token)
+}
+
+/// deletes the reset token and returns the id of the user it belongs to.
+pub async fn consume_password_reset(
+    db: &Database,
+    config: &ApiConfig,
+    token: &str,
+) -> ApiResult<String> {
+    let reset = db
+        .password_resets::<PasswordReset>()
+        .find_one_and_delete(
+            doc! {
+                "_id": hash_token(config, token),
+                "expiresAt": { "$gt": DateTime::now() }
+            },
+            None,
+        )
+        .await
+        .context("consume_password_reset: Failed to find reset token.")?
+        .ok_or(ApiError::InvalidResetToken)?;
+
+    Ok(reset.user_id)
+}
@@ -1,11 +1,20 @@
+use std::time::Duration;
+
 use anyhow::Context;
-use mongodb::bson::{doc, DateTime};
+use futures_util::TryStreamExt;
+use hmac::{Hmac, Mac};
+use mongodb::{
+    bson::{doc, DateTime, Document},
+    options::FindOptions,
+};
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 use ulid::Ulid;
 
 use crate::{
     database::Database,
     util::{
+        config::ApiConfig,
         extractors::auth::AuthUser,
         result::{ApiError, ApiResult},
     },
@@ -18,18 +27,27 @@ use super::user::{self, User};
 pub struct Session {
     #[serde(rename = "_id")]
     pub id: String,
-    pub token: String,
+    /// keyed hash of the token, the token itself is only given to the client.
+    pub token_hash: String,
     pub user_id: String,
     pub expires_at: DateTime,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub name: Option<String>,
+    /// updated every time the session is used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_used_at: Option<DateTime>,
 }
-pub async fn get_user_from_token(db: &Database, token: &str) -> ApiResult<(String, User)> {
+pub async fn get_user_from_token(
+    db: &Database,
+    config: &ApiConfig,
+    token: &str,
+) -> ApiResult<(String, User)> {
     let session = db
         .sessions::<Session>()
         .find_one(
             doc! {
-                "token": &token
+                "tokenHash": hash_token(config, token),
+                "expiresAt": { "$gt": DateTime::now() }
             },
             None,
         )
@@ -43,18 +61,19 @@ pub async fn get_user_from_token(db: &Database, token: &str) -> ApiResult<(Strin
     let user = user::find_user_by_id(db, &session.user_id).await?;
     match user {
         Some(user) => {
-            touch_session(db, &session.id).await?;
+            touch_session(db, config, &session.id).await?;
             Ok((session.id, user))
         }
         None => Err(ApiError::Unauthorized),
     }
 }
-pub async fn validate_token(db: &Database, token: &str) -> ApiResult<AuthUser> {
+pub async fn validate_token(db: &Database, config: &ApiConfig, token: &str) -> ApiResult<AuthUser> {
     let session = db
         .sessions::<Session>()
         .find_one(
             doc! {
-                "token": &token
+                "tokenHash": hash_token(config, token),
+                "expiresAt": { "$gt": DateTime::now() }
             },
             None,
         )
@@ -68,32 +87,32 @@ pub async fn validate_token(db: &Database, token: &str) -> ApiResult<AuthUser> {
     let user = user::find_account_by_id(db, &session.user_id).await?;
     match user {
         Some(user) => {
-            touch_session(db, &session.id).await?;
+            touch_session(db, config, &session.id).await?;
             Ok(AuthUser {
                 id: user.id,
                 username: user.username,
-                password_hash: user.password_hash,
                 session,
             })
         }
         None => Err(ApiError::Unauthorized),
     }
 }
+/// returns the new session and its token.
 pub async fn create_session(
     db: &Database,
+    config: &ApiConfig,
     user_id: &str,
     name: Option<String>,
-) -> ApiResult<Session> {
+) -> ApiResult<(Session, String)> {
     let token = nanoid::nanoid!(50);
     let id = Ulid::new().to_string();
     let session = Session {
+        expires_at: session_expiry(config, &id),
         id,
-        token,
+        token_hash: hash_token(config, &token),
         user_id: user_id.to_string(),
-        expires_at: DateTime::from_millis(
-            DateTime::now().timestamp_millis() + 30 * 24 * 60 * 60 * 1000,
-        ), // TODO: use the config to get this max age.
         name,
+        last_used_at: Some(DateTime::now()),
     };
 
     db.sessions::<Session>()
@@ -101,21 +120,65 @@ pub async fn create_session(
         .await
         .context("create_session: Failed to insert session.")?;
 
-    Ok(session)
+    Ok((session, token))
 }
 
-pub async fn touch_session(db: &Database, sid: &str) -> ApiResult<()> {
-    db.sessions::<Session>().update_one(
-        doc! {
-            "_id": sid
-        },
-        doc! {
-            "$set": {
-                "expiresAt": DateTime::from_millis(DateTime::now().timestamp_millis() + 30 * 24 * 60 * 60 * 1000)
-            }
-        },
-        None
-    ).await.context("touch_session: Failed to update session.")?;
+/// hmac-sha256 of the token, keyed with `session_token_key`.
+pub fn hash_token(config: &ApiConfig, token: &str) -> String {
+    hash_token_with(&config.session_token_key, token)
+}
+
+fn hash_token_with(key: &str, token: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("hmac accepts keys of any length");
+    mac.update(token.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// a session expires after being idle for `session_idle_lifetime`,
+/// but never later than `session_max_lifetime` after it was created.
+fn session_expiry(config: &ApiConfig, sid: &str) -> DateTime {
+    session_expiry_at(
+        DateTime::now().timestamp_millis(),
+        sid,
+        config.session_idle_lifetime,
+        config.session_max_lifetime,
+    )
+}
+
+fn session_expiry_at(
+    now: i64,
+    sid: &str,
+    idle_lifetime: Duration,
+    max_lifetime: Duration,
+) -> DateTime {
+    // session ids are ulids, so they carry their creation time.
+    let created_at = Ulid::from_string(sid)
+        .map(|id| id.timestamp_ms() as i64)
+        .unwrap_or(now);
+
+    DateTime::from_millis(std::cmp::min(
+        now + idle_lifetime.as_millis() as i64,
+        created_at + max_lifetime.as_millis() as i64,
+    ))
+}
+
+pub async fn touch_session(db: &Database, config: &ApiConfig, sid: &str) -> ApiResult<()> {
+    db.sessions::<Session>()
+        .update_one(
+            doc! {
+                "_id": sid
+            },
+            doc! {
+                "$set": {
+                    "expiresAt": session_expiry(config, sid),
+                    "lastUsedAt": DateTime::now()
+                }
+            },
+            None,
+        )
+        .await
+        .context("touch_session: Failed to update session.")?;
     Ok(())
 }
 
@@ -126,3 +189,131 @@ pub async fn delete_session(db: &Database, sid: &str) -> ApiResult<()> {
         .context("delete_session: Failed to delete session.")?;
     Ok(())
 }
+
+/// returns the sessions of `user_id` that haven't expired yet, newest first.
+pub async fn get_sessions_of_user(db: &Database, user_id: &str) -> ApiResult<Vec<Session>> {
+    let sessions = db
+        .sessions::<Session>()
+        .find(
+            doc! {
+                "userId": user_id,
+                "expiresAt": { "$gt": DateTime::now() }
+            },
+            FindOptions::builder().sort(doc! { "_id": -1 }).build(),
+        )
+        .await
+        .context("get_sessions_of_user: Failed to find sessions.")?
+        .try_collect::<Vec<_>>()
+        .await
+        .context("get_sessions_of_user: Failed to iterate over cursor.")?;
+
+    Ok(sessions)
+}
+
+/// deletes one session of `user_id`.
+pub async fn revoke_session(db: &Database, user_id: &str, sid: &str) -> ApiResult<()> {
+    let result = db
+        .sessions::<Session>()
+        .delete_one(doc! { "_id": sid, "userId": user_id }, None)
+        .await
+        .context("revoke_session: Failed to delete session.")?;
+
+    if result.deleted_count == 0 {
+        return Err(ApiError::SessionNotFound);
+    }
+    Ok(())
+}
+
+/// deletes every session of `user_id` except `keep_sid`, returns the ids of the deleted sessions.
+pub async fn revoke_other_sessions(
+    db: &Database,
+    user_id: &str,
+    keep_sid: &str,
+) -> ApiResult<Vec<String>> {
+    revoke_sessions(
+        db,
+        doc! {
+            "userId": user_id,
+            "_id": { "$ne": keep_sid }
+        },
+    )
+    .await
+}
+
+/// deletes every session of `user_id`, returns the ids of the deleted sessions.
+pub async fn revoke_all_sessions(db: &Database, user_id: &str) -> ApiResult<Vec<String>> {
+    revoke_sessions(db, doc! { "userId": user_id }).await
+}
+
+async fn revoke_sessions(db: &Database, filter: Document) -> ApiResult<Vec<String>> {
+    let session_ids = db
+        .sessions::<Session>()
+        .distinct("_id", filter, None)
+        .await
+        .context("revoke_sessions: Failed to find sessions.")?
+        .into_iter()
+        .filter_map(|id| id.as_str().map(|id| id.to_string()))
+        .collect::<Vec<_>>();
+
+    db.sessions::<Session>()
+        .delete_many(doc! { "_id": { "$in": &session_ids } }, None)
+        .await
+        .context("revoke_sessions: Failed to delete sessions.")?;
+
+    Ok(session_ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY_MS: i64 = 24 * 60 * 60 * 1000;
+    const IDLE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+    const MAX: Duration = Duration::from_secs(90 * 24 * 60 * 60);
+
+    fn sid(created_at: i64) -> String {
+        Ulid::from_parts(created_at as u64, 0).to_string()
+    }
+
+    #[test]
+    fn expiry_extends_by_idle_lifetime() {
+        let created_at = 1_700_000_000_000;
+        let now = created_at + 10 * DAY_MS;
+        assert_eq!(
+            session_expiry_at(now, &sid(created_at), IDLE, MAX).timestamp_millis(),
+            now + 30 * DAY_MS
+        );
+    }
+
+    #[test]
+    fn expiry_is_capped_by_max_lifetime() {
+        let created_at = 1_700_000_000_000;
+        let now = created_at + 80 * DAY_MS;
+        assert_eq!(
+            session_expiry_at(now, &sid(created_at), IDLE, MAX).timestamp_millis(),
+            created_at + 90 * DAY_MS
+        );
+    }
+
+    #[test]
+    fn invalid_session_id_counts_as_created_now() {
+        let now = 1_700_000_000_000;
+        assert_eq!(
+            session_expiry_at(now, "not a ulid", IDLE, Duration::from_secs(60)).timestamp_millis(),
+            now + 60 * 1000
+        );
+    }
+
+    #[test]
+    fn token_hash_is_hmac_sha256() {
+        // test case 2 of rfc 4231.
+        assert_eq!(
+            hash_token_with("Jefe", "what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_ne!(
+            hash_token_with("other key", "what do ya want for nothing?"),
+            hash_token_with("Jefe", "what do ya want for nothing?")
+        );
+    }
+}
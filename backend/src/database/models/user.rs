@@ -1,20 +1,35 @@
+use std::fmt;
+
 use anyhow::Context;
 use dashmap::DashMap;
 use futures_util::{future::FutureExt, TryStreamExt};
 use mongodb::{
-    bson::{doc, Document},
-    options::{Collation, CollationStrength, FindOneOptions, FindOptions},
+    bson::{doc, DateTime, Document},
+    options::{
+        Collation, CollationStrength, FindOneAndUpdateOptions, FindOneOptions, FindOptions,
+        ReturnDocument,
+    },
 };
 use serde::{Deserialize, Serialize};
 
 use ulid::Ulid;
 
-use super::chat::{Chat, ChatRecipient};
+use super::{
+    attachment::MessageAttachment,
+    chat::{leave_group_chat_with_session, Chat, ChatRecipient, LeaveGroupResult},
+    invite,
+    mfa::UserMfa,
+};
 use crate::{
     app::UserSocket,
     database::{models::chat::ChatType, Database},
     routes::users::{AddFriendResponse, AddFriendUser, RemoveFriendResponse, RemoveFriendUser},
-    util::result::{ApiError, ApiResult},
+    util::{
+        config::{ApiConfig, RegistrationMode},
+        constants::DELETED_USER_NAME,
+        password::{hash_password, needs_rehash, verify_password},
+        result::{ApiError, ApiResult},
+    },
 };
 
 /// User is the user model with the profile field
@@ -34,11 +49,76 @@ pub struct UserAccount {
     pub id: String,
     pub username: String,
     pub password_hash: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mfa: Option<UserMfa>,
+    /// set when the account was deleted. only the id is kept so chats can still refer to it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<DateTime>,
+    /// set while the account is deactivated. logging in again reactivates it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deactivated_at: Option<DateTime>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username_changed_at: Option<DateTime>,
+    /// the presence the user picked. kept across reconnects.
+    #[serde(default)]
+    pub presence: Presence,
+    /// when the user was last seen online, used when they have no socket since the last restart.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_seen_at: Option<DateTime>,
+}
+
+impl UserAccount {
+    /// deleted and deactivated accounts are hidden from other users.
+    pub fn is_hidden(&self) -> bool {
+        self.deleted_at.is_some() || self.deactivated_at.is_some()
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
 pub struct UserProfile {
+    #[serde(default)]
     pub relations: Vec<Relation>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bio: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avatar: Option<MessageAttachment>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status_text: Option<String>,
+}
+
+impl UserProfile {
+    /// the profile without relations, what other users get to see.
+    pub fn details(&self) -> ProfileDetails {
+        ProfileDetails {
+            display_name: self.display_name.to_owned(),
+            bio: self.bio.to_owned(),
+            avatar: self.avatar.to_owned(),
+            status_text: self.status_text.to_owned(),
+        }
+    }
+}
+
+/// the public fields of a profile. unset fields are sent as `null` so clients can clear them.
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ProfileDetails {
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub avatar: Option<MessageAttachment>,
+    pub status_text: Option<String>,
+}
+
+/// invisible users appear offline to everyone else.
+#[derive(Debug, Default, Clone, Copy, Deserialize, Serialize, PartialEq)]
+pub enum Presence {
+    #[default]
+    Online,
+    Idle,
+    DoNotDisturb,
+    Invisible,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -59,33 +139,44 @@ pub enum RelationStatus {
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
 pub struct UserUsername {
     #[serde(rename = "_id")]
     pub id: String,
     pub username: String,
+    pub deleted_at: Option<DateTime>,
+    pub deactivated_at: Option<DateTime>,
+    pub last_seen_at: Option<DateTime>,
+    pub profile: Option<ProfileDetails>,
 }
 
 #[derive(Debug, Serialize)]
 pub struct RelatedUserStatus {
     pub id: String,
     pub username: String,
+    /// hidden from blocked users and for deleted or deactivated accounts.
+    #[serde(flatten)]
+    pub profile: ProfileDetails,
     pub online: bool,
+    /// `None` while the user appears offline.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence: Option<Presence>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub last_seen_s: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub relationship: Option<RelationStatus>,
 }
 
-impl ToString for RelationStatus {
+impl fmt::Display for RelationStatus {
     // TODO: figure out how to use serde here.
-    fn to_string(&self) -> String {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            RelationStatus::None => "None".to_string(),
-            RelationStatus::Friend => "Friend".to_string(),
-            RelationStatus::Blocked => "Blocked".to_string(),
-            RelationStatus::BlockedByOther => "BlockedByOther".to_string(),
-            RelationStatus::Incoming => "Incoming".to_string(),
-            RelationStatus::Outgoing => "Outgoing".to_string(),
+            RelationStatus::None => write!(f, "None"),
+            RelationStatus::Friend => write!(f, "Friend"),
+            RelationStatus::Blocked => write!(f, "Blocked"),
+            RelationStatus::BlockedByOther => write!(f, "BlockedByOther"),
+            RelationStatus::Incoming => write!(f, "Incoming"),
+            RelationStatus::Outgoing => write!(f, "Outgoing"),
         }
     }
 }
@@ -104,7 +195,17 @@ pub async fn find_related_users_with_status(
                 }
             },
             FindOptions::builder()
-                .projection(doc! { "_id": 1, "username": 1})
+                .projection(doc! {
+                    "_id": 1,
+                    "username": 1,
+                    "deletedAt": 1,
+                    "deactivatedAt": 1,
+                    "lastSeenAt": 1,
+                    "profile.displayName": 1,
+                    "profile.bio": 1,
+                    "profile.avatar": 1,
+                    "profile.statusText": 1
+                })
                 .build(),
         )
         .await
@@ -117,35 +218,107 @@ pub async fn find_related_users_with_status(
         .context("find_related_users_with_status: Failed to get next user from cursor")?
     {
         let relationship = relations.iter().find(|relation| relation.id == user.id);
+        let is_hidden = user.deleted_at.is_some() || user.deactivated_at.is_some();
+        let is_blocked = relationship.is_some_and(|relation| {
+            matches!(
+                relation.status,
+                RelationStatus::Blocked | RelationStatus::BlockedByOther
+            )
+        });
 
-        let (online, last_seen_s) = if let Some(relation) = relationship {
+        let (presence, last_seen_s) = if is_hidden {
+            (None, None)
+        } else if let Some(relation) = relationship {
             if relation.status == RelationStatus::Friend {
-                sockets
-                    .get(&user.id)
-                    .map_or((false, None), |socket| (socket.online, socket.last_seen_s))
+                let stored_last_seen_s = user
+                    .last_seen_at
+                    .map(|last_seen_at| (last_seen_at.timestamp_millis() / 1000) as u64);
+                match sockets.get(&user.id) {
+                    Some(socket) => match socket.visible_presence() {
+                        Some(presence) => (Some(presence), None),
+                        None => (None, socket.last_seen_s.or(stored_last_seen_s)),
+                    },
+                    None => (None, stored_last_seen_s),
+                }
             } else {
-                (false, None)
+                (None, None)
             }
         } else {
-            (false, None)
+            (None, None)
         };
 
         users.push(RelatedUserStatus {
             id: user.id,
-            username: user.username,
-            online,
+            username: if is_hidden {
+                DELETED_USER_NAME.to_string()
+            } else {
+                user.username
+            },
+            profile: if is_hidden || is_blocked {
+                ProfileDetails::default()
+            } else {
+                user.profile.unwrap_or_default()
+            },
+            online: presence.is_some(),
+            presence,
             last_seen_s,
             relationship: relationship.map(|r| r.status.to_owned()),
         });
     }
     Ok(users)
 }
-async fn user_exists_by_username(db: &Database, username: &str) -> ApiResult<bool> {
+/// the relations of `user_id` with the presence of friends, sorted by id.
+/// `statuses` filters the relations when it isn't empty, `after` is the id of the last user of the previous page.
+/// users that blocked `user_id` are never listed, that would tell them apart from other users.
+pub async fn get_relations(
+    db: &Database,
+    user_id: &str,
+    statuses: &[RelationStatus],
+    after: &Option<String>,
+    limit: usize,
+    sockets: &DashMap<String, UserSocket>,
+) -> ApiResult<Vec<RelatedUserStatus>> {
+    let mut relations: Vec<Relation> = find_relations_of_user(db, user_id)
+        .await?
+        .into_iter()
+        .filter(|relation| {
+            if statuses.is_empty() {
+                relation.status != RelationStatus::BlockedByOther
+            } else {
+                statuses.contains(&relation.status)
+            }
+        })
+        .filter(|relation| after.as_ref().is_none_or(|after| relation.id > *after))
+        .collect();
+    relations.sort_by(|a, b| a.id.cmp(&b.id));
+    relations.truncate(limit);
+
+    if relations.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let user_ids: Vec<&str> = relations.iter().map(|r| r.id.as_str()).collect();
+    let mut users = find_related_users_with_status(db, &user_ids, &relations, sockets).await?;
+    users.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(users)
+}
+
+/// `except_id` is left out of the check, so users can change the casing of their own username.
+async fn user_exists_by_username(
+    db: &Database,
+    username: &str,
+    except_id: Option<&str>,
+) -> ApiResult<bool> {
+    let mut filter = doc! { "username": username };
+    if let Some(except_id) = except_id {
+        filter.insert("_id", doc! { "$ne": except_id });
+    }
+
     // find user using en_us collation
     let user = db
         .users::<Document>()
         .find_one(
-            doc! { "username": username },
+            filter,
             FindOneOptions::builder()
                 .collation(
                     Collation::builder()
@@ -190,11 +363,15 @@ pub async fn find_user_by_id(db: &Database, id: &str) -> ApiResult<Option<User>>
     Ok(user)
 }
 
-async fn find_user_by_name(db: &Database, username: &str) -> ApiResult<Option<User>> {
+pub async fn find_user_by_name(db: &Database, username: &str) -> ApiResult<Option<User>> {
     let user = db
         .users::<User>()
         .find_one(
-            doc! { "username": username },
+            doc! {
+                "username": username,
+                "deletedAt": { "$exists": false },
+                "deactivatedAt": { "$exists": false }
+            },
             FindOneOptions::builder()
                 .collation(
                     Collation::builder()
@@ -210,11 +387,11 @@ async fn find_user_by_name(db: &Database, username: &str) -> ApiResult<Option<Us
     Ok(user)
 }
 
-async fn find_account_by_name(db: &Database, username: &str) -> ApiResult<Option<UserAccount>> {
+pub async fn find_account_by_name(db: &Database, username: &str) -> ApiResult<Option<UserAccount>> {
     let account = db
         .users::<UserAccount>()
         .find_one(
-            doc! { "username": username },
+            doc! { "username": username, "deletedAt": { "$exists": false } },
             FindOneOptions::builder()
                 .collation(
                     Collation::builder()
@@ -279,10 +456,21 @@ pub async fn find_relations_of_user(db: &Database, user_id: &str) -> ApiResult<V
         None => Ok(vec![]),
     }
 }
-pub async fn create_user(db: &Database, username: &str, password: &str) -> ApiResult<String> {
-    // TODO: check DISABLE_SIGNUPS env var
+pub async fn create_user(
+    db: &Database,
+    config: &ApiConfig,
+    username: &str,
+    password: &str,
+    invite_code: Option<&str>,
+) -> ApiResult<String> {
+    let invite_code = match config.registration_mode {
+        RegistrationMode::Open => None,
+        RegistrationMode::Closed => return Err(ApiError::SignupDisabled),
+        RegistrationMode::InviteOnly => Some(invite_code.ok_or(ApiError::InvalidInviteCode)?),
+    };
+
     // TODO: use emails + prevent user enumeration.
-    let user_exists = user_exists_by_username(db, username).await?;
+    let user_exists = user_exists_by_username(db, username, None).await?;
 
     if user_exists {
         // warn!(
@@ -292,26 +480,40 @@ pub async fn create_user(db: &Database, username: &str, password: &str) -> ApiRe
         return Err(ApiError::DuplicateUser(None));
     }
 
-    let password_hash = bcrypt::hash(password, bcrypt::DEFAULT_COST)
-        .context("create_user: bcrypt hashing failed")?;
+    if let Some(code) = invite_code {
+        invite::use_invite(db, code).await?;
+    }
+
+    let password_hash = hash_password(config, password)?;
     let user = User {
         account: UserAccount {
             id: Ulid::new().to_string(),
             username: username.to_string(),
             password_hash,
+            mfa: None,
+            deleted_at: None,
+            deactivated_at: None,
+            username_changed_at: None,
+            presence: Presence::Online,
+            last_seen_at: None,
         },
         profile: None,
     };
 
-    db.users::<User>()
-        .insert_one(&user, None)
-        .await
-        .context("create_user: Failed to insert user into database")?;
+    if let Err(err) = db.users::<User>().insert_one(&user, None).await {
+        if let Some(code) = invite_code {
+            invite::release_invite(db, code).await?;
+        }
+        return Err(anyhow::Error::from(err)
+            .context("create_user: Failed to insert user into database")
+            .into());
+    }
     Ok(user.account.id)
 }
 
 pub async fn validate_user(
     db: &Database,
+    config: &ApiConfig,
     username: &str,
     password: &str,
 ) -> ApiResult<UserAccount> {
@@ -322,16 +524,477 @@ pub async fn validate_user(
         None => return Err(ApiError::InvalidCredentials),
     };
 
-    let is_valid = bcrypt::verify(password, &user.password_hash)
-        .context("validate_user: bcrypt verification failed")?;
+    let is_valid = verify_password(config, password, &user.password_hash)?;
 
     if !is_valid {
         return Err(ApiError::InvalidCredentials);
     }
 
+    // upgrades bcrypt hashes and hashes with outdated cost parameters.
+    if needs_rehash(config, &user.password_hash) {
+        set_password(db, config, &user.id, password).await?;
+    }
+
     Ok(user)
 }
 
+async fn confirm_password(
+    db: &Database,
+    config: &ApiConfig,
+    user_id: &str,
+    password: &str,
+) -> ApiResult<User> {
+    let user = find_user_by_id(db, user_id)
+        .await?
+        .ok_or(ApiError::UserNotFound)?;
+
+    if !verify_password(config, password, &user.account.password_hash)? {
+        return Err(ApiError::IncorrectPassword);
+    }
+    Ok(user)
+}
+
+/// what has to be cleaned up after an account was deleted.
+pub struct DeletedAccount {
+    pub relations: Vec<Relation>,
+    pub avatar: Option<MessageAttachment>,
+    /// the group chats the user was removed from.
+    pub left_groups: Vec<LeaveGroupResult>,
+}
+
+/// deletes the account of `user_id`. the document is kept without any personal data so chats can still refer to it.
+/// returns what has to be cleaned up outside of the database.
+pub async fn delete_account(
+    db: &Database,
+    config: &ApiConfig,
+    user_id: &str,
+    password: &str,
+) -> ApiResult<DeletedAccount> {
+    let user = confirm_password(db, config, user_id, password).await?;
+
+    let mut session = db
+        .client
+        .start_session(None)
+        .await
+        .context("delete_account: Failed to start mongodb session")?;
+    let left_groups = session
+        .with_transaction(
+            (
+                &db.users::<User>(),
+                &db.chats::<Chat>(),
+                &db.messages::<Document>(),
+                &db.attachments::<Document>(),
+                &db.sessions::<Document>(),
+                &db.password_resets::<Document>(),
+                &db.mfa_challenges::<Document>(),
+                &db.invites::<Document>(),
+                user_id,
+            ),
+            |session,
+             (
+                users,
+                chats,
+                messages,
+                attachments,
+                sessions,
+                password_resets,
+                mfa_challenges,
+                invites,
+                user_id,
+            )| {
+                async move {
+                    // direct chats are kept, the other recipient sees the author as a deleted user.
+                    let group_chats: Vec<Chat> = chats
+                        .find_with_session(
+                            doc! {
+                                "chatType": ChatType::Group.to_string(),
+                                "recipients.id": *user_id
+                            },
+                            None,
+                            session,
+                        )
+                        .await?
+                        .stream(session)
+                        .try_collect()
+                        .await?;
+                    let mut left_groups = vec![];
+                    for chat in &group_chats {
+                        left_groups.push(
+                            leave_group_chat_with_session(
+                                chats,
+                                messages,
+                                attachments,
+                                session,
+                                chat,
+                                user_id,
+                            )
+                            .await?,
+                        );
+                    }
+
+                    users
+                        .update_many_with_session(
+                            doc! { "profile.relations.id": *user_id },
+                            doc! {
+                                "$pull": {
+                                    "profile.relations": { "id": *user_id }
+                                }
+                            },
+                            None,
+                            session,
+                        )
+                        .await?;
+                    users
+                        .update_one_with_session(
+                            doc! { "_id": *user_id },
+                            doc! {
+                                "$set": {
+                                    "username": DELETED_USER_NAME,
+                                    "passwordHash": "",
+                                    "deletedAt": DateTime::now()
+                                },
+                                "$unset": {
+                                    "profile": "",
+                                    "mfa": "",
+                                    "deactivatedAt": ""
+                                }
+                            },
+                            None,
+                            session,
+                        )
+                        .await?;
+                    for collection in [*sessions, *password_resets, *mfa_challenges] {
+                        collection
+                            .delete_many_with_session(doc! { "userId": *user_id }, None, session)
+                            .await?;
+                    }
+                    invites
+                        .delete_many_with_session(doc! { "creatorId": *user_id }, None, session)
+                        .await?;
+
+                    Ok(left_groups)
+                }
+                .boxed()
+            },
+            None,
+        )
+        .await
+        .context("delete_account: Failed to execute transaction.")?;
+
+    let (relations, avatar) = match user.profile {
+        Some(profile) => (profile.relations, profile.avatar),
+        None => (vec![], None),
+    };
+    Ok(DeletedAccount {
+        relations,
+        avatar,
+        left_groups,
+    })
+}
+
+/// hides the account of `user_id` and revokes its sessions until the user logs in again.
+/// returns the relations of the user.
+pub async fn deactivate_account(
+    db: &Database,
+    config: &ApiConfig,
+    user_id: &str,
+    password: &str,
+) -> ApiResult<Vec<Relation>> {
+    let user = confirm_password(db, config, user_id, password).await?;
+
+    let mut session = db
+        .client
+        .start_session(None)
+        .await
+        .context("deactivate_account: Failed to start mongodb session")?;
+    session
+        .with_transaction(
+            (&db.users::<User>(), &db.sessions::<Document>(), user_id),
+            |session, (users, sessions, user_id)| {
+                async move {
+                    users
+                        .update_one_with_session(
+                            doc! { "_id": *user_id },
+                            doc! { "$set": { "deactivatedAt": DateTime::now() } },
+                            None,
+                            session,
+                        )
+                        .await?;
+                    sessions
+                        .delete_many_with_session(doc! { "userId": *user_id }, None, session)
+                        .await?;
+                    Ok(())
+                }
+                .boxed()
+            },
+            None,
+        )
+        .await
+        .context("deactivate_account: Failed to execute transaction.")?;
+
+    Ok(user.profile.map(|p| p.relations).unwrap_or_default())
+}
+
+pub async fn reactivate_account(db: &Database, user_id: &str) -> ApiResult<()> {
+    db.users::<User>()
+        .update_one(
+            doc! { "_id": user_id, "deactivatedAt": { "$exists": true } },
+            doc! { "$unset": { "deactivatedAt": "" } },
+            None,
+        )
+        .await
+        .context("reactivate_account: Failed to update user")?;
+    Ok(())
+}
+
+/// changes the username of `user_id`, returns the relations of the user.
+pub async fn change_username(
+    db: &Database,
+    config: &ApiConfig,
+    user_id: &str,
+    username: &str,
+) -> ApiResult<Vec<Relation>> {
+    let user = find_user_by_id(db, user_id)
+        .await?
+        .ok_or(ApiError::UserNotFound)?;
+
+    if let Some(changed_at) = user.account.username_changed_at {
+        let next_change_ms =
+            changed_at.timestamp_millis() + config.username_change_cooldown.as_millis() as i64;
+        let wait_ms = next_change_ms - DateTime::now().timestamp_millis();
+        if wait_ms > 0 {
+            return Err(ApiError::UsernameChangeCooldown(
+                (wait_ms as u64).div_ceil(1000),
+            ));
+        }
+    }
+
+    if user_exists_by_username(db, username, Some(user_id)).await? {
+        return Err(ApiError::DuplicateUser(Some(
+            "Username is already taken.".to_string(),
+        )));
+    }
+
+    db.users::<User>()
+        .update_one(
+            doc! { "_id": user_id },
+            doc! {
+                "$set": {
+                    "username": username,
+                    "usernameChangedAt": DateTime::now()
+                }
+            },
+            None,
+        )
+        .await
+        .context("change_username: Failed to update username")?;
+
+    Ok(user.profile.map(|p| p.relations).unwrap_or_default())
+}
+
+pub async fn set_presence(db: &Database, user_id: &str, presence: Presence) -> ApiResult<()> {
+    let presence =
+        mongodb::bson::to_bson(&presence).context("set_presence: Failed to serialize presence")?;
+    db.users::<UserAccount>()
+        .update_one(
+            doc! { "_id": user_id },
+            doc! { "$set": { "presence": presence } },
+            None,
+        )
+        .await
+        .context("set_presence: Failed to update presence")?;
+    Ok(())
+}
+
+pub async fn set_last_seen(db: &Database, user_id: &str, last_seen_s: u64) -> ApiResult<()> {
+    db.users::<UserAccount>()
+        .update_one(
+            doc! { "_id": user_id },
+            doc! {
+                "$set": {
+                    "lastSeenAt": DateTime::from_millis(last_seen_s as i64 * 1000)
+                }
+            },
+            None,
+        )
+        .await
+        .context("set_last_seen: Failed to update last seen")?;
+    Ok(())
+}
+
+/// sets the last seen time of every user in `user_ids` at once.
+pub async fn set_last_seen_many(
+    db: &Database,
+    user_ids: &[String],
+    last_seen_s: u64,
+) -> ApiResult<()> {
+    db.users::<UserAccount>()
+        .update_many(
+            doc! { "_id": { "$in": user_ids } },
+            doc! {
+                "$set": {
+                    "lastSeenAt": DateTime::from_millis(last_seen_s as i64 * 1000)
+                }
+            },
+            None,
+        )
+        .await
+        .context("set_last_seen_many: Failed to update last seen")?;
+    Ok(())
+}
+
+/// finds `user_id` as `viewer_id` sees them. blocked and hidden users only have their id and username.
+pub async fn get_user(
+    db: &Database,
+    viewer_id: &str,
+    user_id: &str,
+    sockets: &DashMap<String, UserSocket>,
+) -> ApiResult<RelatedUserStatus> {
+    let relations = find_relations_of_user(db, viewer_id).await?;
+    find_related_users_with_status(db, &[user_id], &relations, sockets)
+        .await?
+        .pop()
+        .ok_or(ApiError::UserNotFound)
+}
+
+/// updates the given profile fields of `user_id`. empty values clear the field.
+/// returns the updated profile and the relations of the user.
+pub async fn update_profile(
+    db: &Database,
+    user_id: &str,
+    display_name: &Option<String>,
+    bio: &Option<String>,
+    status_text: &Option<String>,
+) -> ApiResult<(ProfileDetails, Vec<Relation>)> {
+    let mut set = Document::new();
+    let mut unset = Document::new();
+    for (field, value) in [
+        ("profile.displayName", display_name),
+        ("profile.bio", bio),
+        ("profile.statusText", status_text),
+    ] {
+        match value.as_deref().map(str::trim) {
+            Some("") => {
+                unset.insert(field, "");
+            }
+            Some(value) => {
+                set.insert(field, value);
+            }
+            None => {}
+        }
+    }
+
+    let mut update = Document::new();
+    if !set.is_empty() {
+        update.insert("$set", set);
+    }
+    if !unset.is_empty() {
+        update.insert("$unset", unset);
+    }
+
+    let user = if update.is_empty() {
+        find_user_by_id(db, user_id).await?
+    } else {
+        db.users::<User>()
+            .find_one_and_update(
+                doc! { "_id": user_id },
+                update,
+                FindOneAndUpdateOptions::builder()
+                    .return_document(ReturnDocument::After)
+                    .build(),
+            )
+            .await
+            .context("update_profile: Failed to update profile")?
+    }
+    .ok_or(ApiError::UserNotFound)?;
+
+    Ok(match user.profile {
+        Some(profile) => (profile.details(), profile.relations),
+        None => (ProfileDetails::default(), vec![]),
+    })
+}
+
+/// replaces the avatar of `user_id`. returns the previous avatar so its file can be deleted,
+/// and the relations of the user.
+pub async fn set_avatar(
+    db: &Database,
+    user_id: &str,
+    avatar: Option<&MessageAttachment>,
+) -> ApiResult<(Option<MessageAttachment>, Vec<Relation>)> {
+    let update = match avatar {
+        Some(avatar) => doc! {
+            "$set": {
+                "profile.avatar": mongodb::bson::to_bson(avatar)
+                    .context("set_avatar: Failed to serialize avatar")?
+            }
+        },
+        None => doc! { "$unset": { "profile.avatar": "" } },
+    };
+
+    let user = db
+        .users::<User>()
+        .find_one_and_update(
+            doc! { "_id": user_id },
+            update,
+            FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::Before)
+                .build(),
+        )
+        .await
+        .context("set_avatar: Failed to update avatar")?
+        .ok_or(ApiError::UserNotFound)?;
+
+    Ok(match user.profile {
+        Some(profile) => (profile.avatar, profile.relations),
+        None => (None, vec![]),
+    })
+}
+
+/// changes the password of `user_id` if `current_password` is correct.
+pub async fn change_password(
+    db: &Database,
+    config: &ApiConfig,
+    user_id: &str,
+    current_password: &str,
+    new_password: &str,
+) -> ApiResult<()> {
+    let user = find_account_by_id(db, user_id)
+        .await?
+        .ok_or(ApiError::UserNotFound)?;
+
+    let is_valid = verify_password(config, current_password, &user.password_hash)?;
+
+    if !is_valid {
+        return Err(ApiError::IncorrectPassword);
+    }
+
+    set_password(db, config, user_id, new_password).await
+}
+
+pub async fn set_password(
+    db: &Database,
+    config: &ApiConfig,
+    user_id: &str,
+    password: &str,
+) -> ApiResult<()> {
+    let password_hash = hash_password(config, password)?;
+
+    let result = db
+        .users::<UserAccount>()
+        .update_one(
+            doc! { "_id": user_id },
+            doc! { "$set": { "passwordHash": password_hash } },
+            None,
+        )
+        .await
+        .context("set_password: Failed to update password")?;
+
+    if result.matched_count == 0 {
+        return Err(ApiError::UserNotFound);
+    }
+    Ok(())
+}
+
 pub async fn add_friend(
     db: &Database,
     receiver_username_or_id: &str,
@@ -346,6 +1009,10 @@ pub async fn add_friend(
     }
     .ok_or(ApiError::UserNotFound)?;
 
+    if receiver_user.account.is_hidden() {
+        return Err(ApiError::UserNotFound);
+    }
+
     if receiver_user.account.id == *sender_id {
         return Err(ApiError::CantAddSelf);
     }
@@ -424,12 +1091,18 @@ pub async fn add_friend(
                                     let chat = Chat {
                                         id: Ulid::new().to_string(),
                                         chat_type: ChatType::Direct,
+                                        name: None,
+                                        owner_id: None,
                                         recipients: vec![
                                             ChatRecipient {
                                                 id: receiver_id.to_string(),
+                                                role: None,
+                                                last_read_id: None,
                                             },
                                             ChatRecipient {
                                                 id: sender_id.to_string(),
+                                                role: None,
+                                                last_read_id: None,
                                             },
                                         ],
                                         last_message_id: None,
@@ -655,3 +1328,212 @@ pub async fn remove_friend(
         }
     }
 }
+
+/// the relationship of both users after it was changed.
+pub struct RelationUpdate {
+    /// how the user who made the change sees the other user.
+    pub status: RelationStatus,
+    /// how the other user sees the user who made the change.
+    pub receiver_status: RelationStatus,
+    /// the direct chat of the users if they were friends.
+    pub chat_id: Option<String>,
+}
+
+fn relation_status_with(user: &User, other_id: &str) -> RelationStatus {
+    user.profile
+        .as_ref()
+        .and_then(|profile| {
+            profile
+                .relations
+                .iter()
+                .find(|relation| relation.id == other_id)
+        })
+        .map_or(RelationStatus::None, |relation| relation.status.to_owned())
+}
+
+/// replaces the relation `user_id` has with `other_id`. `RelationStatus::None` only removes it.
+async fn replace_relation(
+    users: &mongodb::Collection<User>,
+    session: &mut mongodb::ClientSession,
+    user_id: &str,
+    other_id: &str,
+    status: &RelationStatus,
+) -> mongodb::error::Result<()> {
+    users
+        .update_one_with_session(
+            doc! { "_id": user_id },
+            doc! { "$pull": { "profile.relations": { "id": other_id } } },
+            None,
+            session,
+        )
+        .await?;
+
+    if *status != RelationStatus::None {
+        users
+            .update_one_with_session(
+                doc! { "_id": user_id },
+                doc! {
+                    "$push": {
+                        "profile.relations": {
+                            "id": other_id,
+                            "status": status.to_string()
+                        }
+                    }
+                },
+                None,
+                session,
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+/// blocks `receiver_id`, replacing any friendship or pending friend request between the users.
+pub async fn block_user(
+    db: &Database,
+    receiver_id: &str,
+    sender_id: &str,
+) -> ApiResult<RelationUpdate> {
+    if receiver_id == sender_id {
+        return Err(ApiError::CantBlockSelf);
+    }
+    let sender_user = find_user_by_id(db, sender_id)
+        .await?
+        .ok_or(ApiError::UserNotFound)?;
+    let receiver_user = find_user_by_id(db, receiver_id)
+        .await?
+        .ok_or(ApiError::UserNotFound)?;
+
+    if receiver_user.account.is_hidden() {
+        return Err(ApiError::UserNotFound);
+    }
+
+    let status = relation_status_with(&sender_user, receiver_id);
+    if status == RelationStatus::Blocked {
+        return Err(ApiError::BlockedFriend);
+    }
+    // if both users block each other, the block of the receiver is kept.
+    let receiver_status = match relation_status_with(&receiver_user, sender_id) {
+        RelationStatus::Blocked => RelationStatus::Blocked,
+        _ => RelationStatus::BlockedByOther,
+    };
+
+    let mut session = db
+        .client
+        .start_session(None)
+        .await
+        .context("block_user: Failed to start mongodb session")?;
+
+    let chat_id = session
+        .with_transaction(
+            (
+                &db.users::<User>(),
+                &db.chats::<Chat>(),
+                sender_id,
+                receiver_id,
+                status,
+                &receiver_status,
+            ),
+            |session, (users, chats, sender_id, receiver_id, status, receiver_status)| {
+                async move {
+                    replace_relation(
+                        users,
+                        session,
+                        sender_id,
+                        receiver_id,
+                        &RelationStatus::Blocked,
+                    )
+                    .await?;
+                    replace_relation(users, session, receiver_id, sender_id, receiver_status)
+                        .await?;
+
+                    if *status != RelationStatus::Friend {
+                        return Ok(None);
+                    }
+                    let chat = chats
+                        .find_one_with_session(
+                            doc! {
+                                "chatType": ChatType::Direct.to_string(),
+                                "recipients.id": {
+                                    "$all": [*sender_id, *receiver_id]
+                                }
+                            },
+                            None,
+                            session,
+                        )
+                        .await?;
+                    Ok(chat.map(|chat| chat.id))
+                }
+                .boxed()
+            },
+            None,
+        )
+        .await
+        .context("block_user: Failed to block user: transaction failed")?;
+
+    Ok(RelationUpdate {
+        status: RelationStatus::Blocked,
+        receiver_status,
+        chat_id,
+    })
+}
+
+/// removes the block `sender_id` placed on `receiver_id`.
+pub async fn unblock_user(
+    db: &Database,
+    receiver_id: &str,
+    sender_id: &str,
+) -> ApiResult<RelationUpdate> {
+    let sender_user = find_user_by_id(db, sender_id)
+        .await?
+        .ok_or(ApiError::UserNotFound)?;
+    if relation_status_with(&sender_user, receiver_id) != RelationStatus::Blocked {
+        return Err(ApiError::NotBlocked);
+    }
+    let receiver_user = find_user_by_id(db, receiver_id)
+        .await?
+        .ok_or(ApiError::UserNotFound)?;
+
+    // the sender stays blocked if the receiver blocked them too.
+    let (status, receiver_status) = match relation_status_with(&receiver_user, sender_id) {
+        RelationStatus::Blocked => (RelationStatus::BlockedByOther, RelationStatus::Blocked),
+        _ => (RelationStatus::None, RelationStatus::None),
+    };
+
+    let mut session = db
+        .client
+        .start_session(None)
+        .await
+        .context("unblock_user: Failed to start mongodb session")?;
+
+    session
+        .with_transaction(
+            (
+                &db.users::<User>(),
+                sender_id,
+                receiver_id,
+                &status,
+                &receiver_status,
+            ),
+            |session, (users, sender_id, receiver_id, status, receiver_status)| {
+                async move {
+                    replace_relation(users, session, sender_id, receiver_id, status).await?;
+                    if **receiver_status == RelationStatus::None {
+                        replace_relation(users, session, receiver_id, sender_id, receiver_status)
+                            .await?;
+                    }
+                    Ok(())
+                }
+                .boxed()
+            },
+            None,
+        )
+        .await
+        .context("unblock_user: Failed to unblock user: transaction failed")?;
+
+    Ok(RelationUpdate {
+        status,
+        receiver_status,
+        chat_id: None,
+    })
+}
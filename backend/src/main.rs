@@ -1,4 +1,4 @@
-use std::process;
+use std::{net::SocketAddr, process};
 
 use tracing::*;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
@@ -6,7 +6,9 @@ use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use util::{config::ApiConfig, constants::EVENT_SYS_CRASH};
 mod app;
 mod database;
+mod notifier;
 mod routes;
+mod storage;
 mod util;
 
 #[tokio::main]
@@ -30,7 +32,7 @@ async fn main() {
 
     info!("Listening on {}", &config.socket_address);
     axum::Server::bind(&config.socket_address)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .unwrap();
 }
@@ -0,0 +1,45 @@
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use axum::async_trait;
+use serde_json::json;
+use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+
+use super::Notifier;
+
+/// appends every notification as a json line to a local file. stands in for email delivery.
+pub struct FileNotifier {
+    path: PathBuf,
+}
+
+impl FileNotifier {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    async fn append(&self, notification: serde_json::Value) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await
+            .context("FileNotifier::append: Failed to open notification file.")?;
+        file.write_all(format!("{notification}\n").as_bytes())
+            .await
+            .context("FileNotifier::append: Failed to write notification.")?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Notifier for FileNotifier {
+    async fn send_password_reset(&self, user_id: &str, username: &str, token: &str) -> Result<()> {
+        self.append(json!({
+            "event": "PasswordReset",
+            "userId": user_id,
+            "username": username,
+            "token": token
+        }))
+        .await
+    }
+}
@@ -0,0 +1,10 @@
+pub mod file;
+
+use anyhow::Result;
+use axum::async_trait;
+
+/// Notifier delivers messages that have to reach the user outside of the app, like password reset tokens.
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    async fn send_password_reset(&self, user_id: &str, username: &str, token: &str) -> Result<()>;
+}
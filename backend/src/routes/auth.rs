@@ -1,25 +1,51 @@
 use axum::routing::get;
 use axum::{
-    extract::State,
-    routing::{delete, post},
+    extract::{Path, State},
+    routing::{delete, post, put},
     Json, Router,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use tracing::warn;
+use ulid::Ulid;
 use validator::Validate;
 
-use crate::database::models::session;
-use crate::util::extractors::auth::AuthUser;
-use crate::{app::AppState, util::extractors::json::JsonExtractor, util::result::ApiResult};
+use crate::database::models::{
+    invite::{self, Invite},
+    mfa, password_reset, session,
+};
+use crate::util::extractors::{auth::AuthUser, client_ip::ClientIp};
+use crate::{
+    app::AppState,
+    util::extractors::json::JsonExtractor,
+    util::result::{ApiError, ApiResult},
+};
 
-use crate::{database::models::user, util::constants::USERNAME_REGEX};
+use crate::{
+    database::models::user,
+    util::constants::{INVITE_DEFAULT_LIFETIME_S, USERNAME_REGEX},
+};
 
 pub fn build_router() -> Router<AppState> {
     Router::new()
         .route("/signup", post(create_user))
         .route("/user", get(get_user))
         .route("/login", post(login))
+        .route("/login/mfa", post(login_mfa))
         .route("/logout", delete(logout))
+        .route("/sessions", get(get_sessions).delete(revoke_other_sessions))
+        .route("/sessions/:sessionId", delete(revoke_session))
+        .route("/password", put(change_password))
+        .route("/password/reset", post(request_password_reset))
+        .route("/password/reset/confirm", post(reset_password))
+        .route(
+            "/mfa/totp",
+            post(begin_totp_enrollment).put(confirm_totp_enrollment),
+        )
+        .route("/mfa", delete(disable_mfa))
+        .route("/mfa/recovery-codes", post(regenerate_recovery_codes))
+        .route("/invites", get(get_invites).post(create_invite))
+        .route("/invites/:code", delete(delete_invite))
 }
 
 #[axum::debug_handler]
@@ -27,7 +53,14 @@ async fn create_user(
     State(state): State<AppState>,
     JsonExtractor(body): JsonExtractor<UserCredentialsRequest>,
 ) -> ApiResult<Json<CreateUserResponse>> {
-    let user_id = user::create_user(&state.db, &body.username, &body.password).await?;
+    let user_id = user::create_user(
+        &state.db,
+        &state.config,
+        &body.username,
+        &body.password,
+        body.invite_code.as_deref(),
+    )
+    .await?;
     Ok(Json(CreateUserResponse {
         id: user_id,
         username: body.username,
@@ -44,41 +77,366 @@ async fn get_user(auth: AuthUser) -> Json<GetUserResponse> {
 
 async fn login(
     State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
     JsonExtractor(body): JsonExtractor<LoginRequest>,
 ) -> ApiResult<Json<LoginResponse>> {
-    let user = user::validate_user(&state.db, &body.username, &body.password).await?;
-    let session = session::create_session(&state.db, &user.id, body.friendly_name).await?;
+    // checked before validating the password, hashing is expensive.
+    state.login_limiter.check(&body.username, ip)?;
+    let user =
+        match user::validate_user(&state.db, &state.config, &body.username, &body.password).await {
+            Err(ApiError::InvalidCredentials) => {
+                state.login_limiter.record_failure(&body.username, ip);
+                return Err(ApiError::InvalidCredentials);
+            }
+            result => result?,
+        };
+
+    // failures are only forgotten once the second factor is verified too.
+    if user.mfa.as_ref().is_some_and(|mfa| mfa.enabled) {
+        let ticket =
+            mfa::create_mfa_challenge(&state.db, &state.config, &user.id, body.friendly_name)
+                .await?;
+        return Ok(Json(LoginResponse::MfaRequired {
+            mfa_required: true,
+            ticket,
+        }));
+    }
+
+    state.login_limiter.record_success(&body.username, &user.id);
+    user::reactivate_account(&state.db, &user.id).await?;
+    let (session, token) =
+        session::create_session(&state.db, &state.config, &user.id, body.friendly_name).await?;
+
+    Ok(Json(LoginResponse::Session {
+        id: user.id,
+        username: user.username,
+        session: SessionLoginResponse {
+            id: session.id,
+            token,
+        },
+    }))
+}
+
+async fn login_mfa(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    JsonExtractor(body): JsonExtractor<LoginMfaRequest>,
+) -> ApiResult<Json<LoginResponse>> {
+    let challenge = mfa::find_mfa_challenge(&state.db, &state.config, &body.ticket).await?;
+    state.login_limiter.check_mfa(&challenge.user_id, ip)?;
 
-    Ok(Json(LoginResponse {
+    let challenge =
+        match mfa::complete_mfa_challenge(&state.db, &state.config, &body.ticket, &body.code).await
+        {
+            Err(ApiError::InvalidMfaCode) => {
+                state
+                    .login_limiter
+                    .record_mfa_failure(&challenge.user_id, ip);
+                return Err(ApiError::InvalidMfaCode);
+            }
+            result => result?,
+        };
+    let user = user::find_account_by_id(&state.db, &challenge.user_id)
+        .await?
+        .ok_or(ApiError::InvalidMfaTicket)?;
+    state.login_limiter.record_success(&user.username, &user.id);
+    user::reactivate_account(&state.db, &user.id).await?;
+    let (session, token) =
+        session::create_session(&state.db, &state.config, &user.id, challenge.name).await?;
+
+    Ok(Json(LoginResponse::Session {
         id: user.id,
         username: user.username,
         session: SessionLoginResponse {
             id: session.id,
-            token: session.token,
+            token,
         },
     }))
 }
 
+async fn begin_totp_enrollment(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    JsonExtractor(body): JsonExtractor<ConfirmPasswordRequest>,
+) -> ApiResult<Json<TotpEnrollmentResponse>> {
+    let enrollment =
+        mfa::begin_totp_enrollment(&state.db, &state.config, &auth.id, &body.password).await?;
+
+    Ok(Json(TotpEnrollmentResponse {
+        secret: enrollment.secret,
+        uri: enrollment.uri,
+    }))
+}
+
+async fn confirm_totp_enrollment(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    JsonExtractor(body): JsonExtractor<ConfirmTotpEnrollmentRequest>,
+) -> ApiResult<Json<RecoveryCodesResponse>> {
+    let recovery_codes = mfa::confirm_totp_enrollment(
+        &state.db,
+        &state.config,
+        &auth.id,
+        &body.password,
+        &body.code,
+    )
+    .await?;
+
+    Ok(Json(RecoveryCodesResponse { recovery_codes }))
+}
+
+async fn disable_mfa(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    JsonExtractor(body): JsonExtractor<MfaCodeRequest>,
+) -> ApiResult<Json<Value>> {
+    mfa::disable_mfa(&state.db, &state.config, &auth.id, &body.code).await?;
+
+    Ok(Json(json!({
+        "message": "Successfully disabled two-factor authentication."
+    })))
+}
+
+async fn regenerate_recovery_codes(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    JsonExtractor(body): JsonExtractor<MfaCodeRequest>,
+) -> ApiResult<Json<RecoveryCodesResponse>> {
+    let recovery_codes =
+        mfa::regenerate_recovery_codes(&state.db, &state.config, &auth.id, &body.code).await?;
+
+    Ok(Json(RecoveryCodesResponse { recovery_codes }))
+}
+
+async fn create_invite(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    JsonExtractor(body): JsonExtractor<CreateInviteRequest>,
+) -> ApiResult<Json<InviteResponse>> {
+    let invite = invite::create_invite(
+        &state.db,
+        &auth.id,
+        body.max_uses.unwrap_or(1),
+        body.expires_in_s.unwrap_or(INVITE_DEFAULT_LIFETIME_S),
+    )
+    .await?;
+
+    Ok(Json(invite.into()))
+}
+
+async fn get_invites(
+    State(state): State<AppState>,
+    auth: AuthUser,
+) -> ApiResult<Json<Vec<InviteResponse>>> {
+    let invites = invite::get_invites_of_user(&state.db, &auth.id).await?;
+
+    Ok(Json(
+        invites.into_iter().map(InviteResponse::from).collect(),
+    ))
+}
+
+async fn delete_invite(
+    State(state): State<AppState>,
+    Path(code): Path<String>,
+    auth: AuthUser,
+) -> ApiResult<Json<Value>> {
+    invite::delete_invite(&state.db, &auth.id, &code).await?;
+
+    Ok(Json(json!({
+        "message": "Successfully deleted invite."
+    })))
+}
+
 #[axum::debug_handler(state = AppState)]
 async fn logout(State(state): State<AppState>, auth: AuthUser) -> ApiResult<Json<Value>> {
     session::delete_session(&state.db, &auth.session.id).await?;
+    state.close_sessions(&auth.id, &[auth.session.id]);
 
     Ok(Json(json!({
         "message": "Successfully logged out."
     })))
 }
 
+async fn get_sessions(
+    State(state): State<AppState>,
+    auth: AuthUser,
+) -> ApiResult<Json<Vec<SessionResponse>>> {
+    let sessions = session::get_sessions_of_user(&state.db, &auth.id).await?;
+
+    Ok(Json(
+        sessions
+            .into_iter()
+            .map(|session| SessionResponse {
+                current: session.id == auth.session.id,
+                created_at: Ulid::from_string(&session.id)
+                    .map(|id| id.timestamp_ms())
+                    .unwrap_or_default(),
+                last_used_at: session.last_used_at.map(|t| t.timestamp_millis() as u64),
+                expires_at: session.expires_at.timestamp_millis() as u64,
+                id: session.id,
+                name: session.name,
+            })
+            .collect(),
+    ))
+}
+
+async fn revoke_session(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    auth: AuthUser,
+) -> ApiResult<Json<Value>> {
+    session::revoke_session(&state.db, &auth.id, &session_id).await?;
+    state.close_sessions(&auth.id, &[session_id]);
+
+    Ok(Json(json!({
+        "message": "Successfully revoked session."
+    })))
+}
+
+async fn revoke_other_sessions(
+    State(state): State<AppState>,
+    auth: AuthUser,
+) -> ApiResult<Json<Value>> {
+    let session_ids = session::revoke_other_sessions(&state.db, &auth.id, &auth.session.id).await?;
+    state.close_sessions(&auth.id, &session_ids);
+
+    Ok(Json(json!({
+        "message": "Successfully revoked other sessions.",
+        "count": session_ids.len()
+    })))
+}
+
+async fn change_password(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    JsonExtractor(body): JsonExtractor<ChangePasswordRequest>,
+) -> ApiResult<Json<Value>> {
+    user::change_password(
+        &state.db,
+        &state.config,
+        &auth.id,
+        &body.current_password,
+        &body.new_password,
+    )
+    .await?;
+    let session_ids = session::revoke_other_sessions(&state.db, &auth.id, &auth.session.id).await?;
+    state.close_sessions(&auth.id, &session_ids);
+
+    Ok(Json(json!({
+        "message": "Successfully changed password."
+    })))
+}
+
+async fn request_password_reset(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    JsonExtractor(body): JsonExtractor<PasswordResetRequest>,
+) -> ApiResult<Json<Value>> {
+    state
+        .login_limiter
+        .check_password_reset(&body.username, ip)?;
+
+    // the reset is sent off the request path, so neither the response nor its timing
+    // tell whether the user exists.
+    tokio::spawn(async move {
+        if let Err(err) = send_password_reset(&state, &body.username).await {
+            warn!("Failed to send password reset: {:?}", err);
+        }
+    });
+
+    Ok(Json(json!({
+        "message": "If the account exists, a password reset token has been sent."
+    })))
+}
+
+async fn send_password_reset(state: &AppState, username: &str) -> ApiResult<()> {
+    if let Some(user) = user::find_account_by_name(&state.db, username).await? {
+        let token =
+            password_reset::create_password_reset(&state.db, &state.config, &user.id).await?;
+        state
+            .notifier
+            .send_password_reset(&user.id, &user.username, &token)
+            .await?;
+    }
+    Ok(())
+}
+
+async fn reset_password(
+    State(state): State<AppState>,
+    JsonExtractor(body): JsonExtractor<ResetPasswordRequest>,
+) -> ApiResult<Json<Value>> {
+    let user_id =
+        password_reset::consume_password_reset(&state.db, &state.config, &body.token).await?;
+    user::set_password(&state.db, &state.config, &user_id, &body.new_password).await?;
+    let session_ids = session::revoke_all_sessions(&state.db, &user_id).await?;
+    state.close_sessions(&user_id, &session_ids);
+
+    Ok(Json(json!({
+        "message": "Successfully reset password."
+    })))
+}
+
 #[derive(Serialize)]
-struct LoginResponse {
-    id: String,
-    username: String,
-    session: SessionLoginResponse,
+#[serde(untagged)]
+enum LoginResponse {
+    Session {
+        id: String,
+        username: String,
+        session: SessionLoginResponse,
+    },
+    /// the password was correct, the ticket has to be exchanged for a session with `/login/mfa`.
+    #[serde(rename_all = "camelCase")]
+    MfaRequired { mfa_required: bool, ticket: String },
 }
 #[derive(Serialize)]
 struct SessionLoginResponse {
     id: String,
     token: String,
 }
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct InviteResponse {
+    code: String,
+    max_uses: i32,
+    uses: i32,
+    /// unix timestamp in milliseconds.
+    expires_at: u64,
+}
+impl From<Invite> for InviteResponse {
+    fn from(invite: Invite) -> Self {
+        InviteResponse {
+            code: invite.code,
+            max_uses: invite.max_uses,
+            uses: invite.uses,
+            expires_at: invite.expires_at.timestamp_millis() as u64,
+        }
+    }
+}
+#[derive(Serialize)]
+struct TotpEnrollmentResponse {
+    secret: String,
+    uri: String,
+}
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RecoveryCodesResponse {
+    recovery_codes: Vec<String>,
+}
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SessionResponse {
+    id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    /// unix timestamp in milliseconds.
+    created_at: u64,
+    /// unix timestamp in milliseconds.
+    last_used_at: Option<u64>,
+    /// unix timestamp in milliseconds.
+    expires_at: u64,
+    /// whether this is the session making the request.
+    current: bool,
+}
 #[derive(Deserialize, Serialize)]
 struct GetUserResponse {
     id: String,
@@ -100,6 +458,7 @@ struct LoginRequest {
     friendly_name: Option<String>,
 }
 #[derive(Deserialize, Serialize, Validate)]
+#[serde(rename_all = "camelCase")]
 struct UserCredentialsRequest {
     #[validate(
         length(
@@ -115,6 +474,66 @@ struct UserCredentialsRequest {
     username: String,
     #[validate(length(min = 8, message = "Must be atleast 8 characters long."))]
     password: String,
+    /// only needed in invite-only registration mode.
+    #[validate(length(max = 32, message = "Invalid invite code."))]
+    invite_code: Option<String>,
+}
+
+#[derive(Deserialize, Validate)]
+#[serde(rename_all = "camelCase")]
+struct ChangePasswordRequest {
+    current_password: String,
+    #[validate(length(min = 8, message = "Must be atleast 8 characters long."))]
+    new_password: String,
+}
+#[derive(Deserialize, Validate)]
+struct PasswordResetRequest {
+    #[validate(length(max = 32, message = "Must be atmost 32 characters long."))]
+    username: String,
+}
+#[derive(Deserialize, Validate)]
+#[serde(rename_all = "camelCase")]
+struct ResetPasswordRequest {
+    #[validate(length(equal = 50, message = "Invalid token."))]
+    token: String,
+    #[validate(length(min = 8, message = "Must be atleast 8 characters long."))]
+    new_password: String,
+}
+
+#[derive(Deserialize, Validate)]
+struct LoginMfaRequest {
+    #[validate(length(equal = 50, message = "Invalid ticket."))]
+    ticket: String,
+    #[validate(length(min = 6, max = 11, message = "Invalid code."))]
+    code: String,
+}
+#[derive(Deserialize, Validate)]
+#[serde(rename_all = "camelCase")]
+struct CreateInviteRequest {
+    #[validate(range(min = 1, max = 100, message = "Must be between 1 and 100."))]
+    max_uses: Option<i32>,
+    /// seconds until the invite expires.
+    #[validate(range(
+        min = 60,
+        max = 2592000,
+        message = "Must be between 1 minute and 30 days."
+    ))]
+    expires_in_s: Option<i64>,
+}
+#[derive(Deserialize, Validate)]
+struct MfaCodeRequest {
+    #[validate(length(min = 6, max = 11, message = "Invalid code."))]
+    code: String,
+}
+#[derive(Deserialize, Validate)]
+struct ConfirmPasswordRequest {
+    password: String,
+}
+#[derive(Deserialize, Validate)]
+struct ConfirmTotpEnrollmentRequest {
+    password: String,
+    #[validate(length(min = 6, max = 11, message = "Invalid code."))]
+    code: String,
 }
 
 #[derive(serde::Serialize)]
@@ -1,27 +1,154 @@
 use axum::{
-    extract::{Path, State},
-    routing::get,
+    extract::{multipart::MultipartRejection, DefaultBodyLimit, Multipart, Path, State},
+    response::IntoResponse,
+    routing::{get, patch, post, put},
     Json, Router,
 };
+use http::header;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use validator::Validate;
 
 use crate::{
     app::AppState,
-    database::models::message,
+    database::models::{
+        attachment::{self, MessageAttachment},
+        chat::{self, ChatRole},
+        message,
+    },
     util::{
+        config::ApiConfig,
+        constants::REACTION_SHORTCODE_REGEX,
         extractors::{auth::AuthUser, json::JsonExtractor, query::Query},
-        result::ApiResult,
+        permissions::ChatPermissions,
+        result::{ApiError, ApiResult},
     },
 };
 
-use super::ws;
+use super::{users::ChatJson, ws};
 
 pub fn build_router() -> Router<AppState> {
-    Router::new().route(
-        "/:chatId/messages",
-        get(get_messages).post(save_direct_message),
-    )
+    Router::new()
+        .route("/", post(create_group_chat))
+        .route("/search", get(search_all_messages))
+        .route("/:chatId/messages", get(get_messages).post(save_message))
+        .route("/:chatId/messages/search", get(search_chat_messages))
+        .route(
+            "/:chatId/messages/:messageId",
+            patch(edit_message).delete(delete_message),
+        )
+        .route(
+            "/:chatId/attachments",
+            // the size limit is enforced while reading the upload, see `read_upload`.
+            post(upload_attachment).layer(DefaultBodyLimit::disable()),
+        )
+        .route("/:chatId/attachments/:attachmentId", get(get_attachment))
+        .route(
+            "/:chatId/messages/:messageId/reactions/:emoji",
+            put(add_reaction).delete(remove_reaction),
+        )
+        .route(
+            "/:chatId/recipients/:userId",
+            put(add_recipient).delete(remove_recipient),
+        )
+        .route("/:chatId/recipients/:userId/role", put(set_recipient_role))
+        .route("/:chatId/owner", put(transfer_ownership))
+        .route("/:chatId/read", put(mark_chat_read))
+}
+
+async fn create_group_chat(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    JsonExtractor(body): JsonExtractor<CreateGroupChatRequest>,
+) -> ApiResult<Json<ChatJson>> {
+    let chat =
+        chat::create_group_chat(&state.db, &auth.id, body.name.trim(), &body.recipients).await?;
+    let chat: ChatJson = chat.into();
+    ws::emit_new_group_chat(&state, &chat);
+    Ok(Json(chat))
+}
+
+async fn add_recipient(
+    State(state): State<AppState>,
+    Path((chat_id, user_id)): Path<(String, String)>,
+    auth: AuthUser,
+) -> ApiResult<Json<ChatJson>> {
+    let chat: ChatJson = chat::add_group_recipient(&state.db, &chat_id, &auth.id, &user_id)
+        .await?
+        .into();
+    ws::emit_group_recipient_added(&state, &chat, &user_id);
+    Ok(Json(chat))
+}
+
+/// kicks a member, or leaves the group when `userId` is `@me` or the caller's own id.
+async fn remove_recipient(
+    State(state): State<AppState>,
+    Path((chat_id, user_id)): Path<(String, String)>,
+    auth: AuthUser,
+) -> ApiResult<Json<Value>> {
+    if user_id == "@me" || user_id == auth.id {
+        let result = chat::leave_group_chat(&state.db, &chat_id, &auth.id).await?;
+        attachment::delete_attachment_files(state.storage.as_ref(), &result.attachment_ids).await;
+        ws::emit_group_recipient_removed(&state, &chat_id, &auth.id);
+        if let Some(ref new_owner_id) = result.new_owner_id {
+            ws::emit_group_owner_changed(&state, &chat_id, new_owner_id);
+        }
+        let message = if result.deleted {
+            "Successfully left the group. The group was deleted as it has no members left."
+        } else {
+            "Successfully left the group."
+        };
+        return Ok(Json(json!({ "message": message })));
+    }
+
+    chat::remove_group_recipient(&state.db, &chat_id, &auth.id, &user_id).await?;
+    ws::emit_group_recipient_removed(&state, &chat_id, &user_id);
+    Ok(Json(json!({
+        "message": "Successfully removed the user from the group."
+    })))
+}
+
+async fn set_recipient_role(
+    State(state): State<AppState>,
+    Path((chat_id, user_id)): Path<(String, String)>,
+    auth: AuthUser,
+    JsonExtractor(body): JsonExtractor<SetRecipientRoleRequest>,
+) -> ApiResult<Json<ChatJson>> {
+    let chat: ChatJson =
+        chat::set_group_recipient_role(&state.db, &chat_id, &auth.id, &user_id, body.role.clone())
+            .await?
+            .into();
+    ws::emit_group_recipient_role_changed(&state, &chat.id, &user_id, &body.role);
+    Ok(Json(chat))
+}
+
+async fn transfer_ownership(
+    State(state): State<AppState>,
+    Path(chat_id): Path<String>,
+    auth: AuthUser,
+    JsonExtractor(body): JsonExtractor<TransferOwnershipRequest>,
+) -> ApiResult<Json<ChatJson>> {
+    let chat: ChatJson =
+        chat::transfer_group_ownership(&state.db, &chat_id, &auth.id, &body.user_id)
+            .await?
+            .into();
+    ws::emit_group_owner_changed(&state, &chat.id, &body.user_id);
+    Ok(Json(chat))
+}
+
+async fn mark_chat_read(
+    State(state): State<AppState>,
+    Path(chat_id): Path<String>,
+    auth: AuthUser,
+    JsonExtractor(body): JsonExtractor<MarkChatReadRequest>,
+) -> ApiResult<Json<Value>> {
+    if chat::mark_chat_read(&state.db, &auth.id, &chat_id, &body.message_id).await? {
+        ws::emit_chat_read(&state, &chat_id, &auth.id, &body.message_id);
+    }
+    Ok(Json(json!({
+        "chatId": chat_id,
+        "messageId": body.message_id
+    })))
 }
 
 async fn get_messages(
@@ -43,26 +170,262 @@ async fn get_messages(
     Ok(Json(messages))
 }
 
-async fn save_direct_message(
+async fn search_all_messages(
+    State(state): State<AppState>,
+    Query(query): Query<SearchMessagesQuery>,
+    auth: AuthUser,
+) -> ApiResult<Json<Vec<MessageJson>>> {
+    let messages = message::search_messages(&state.db, &auth.id, None, &query).await?;
+    Ok(Json(messages))
+}
+
+async fn search_chat_messages(
+    State(state): State<AppState>,
+    Path(chat_id): Path<String>,
+    Query(query): Query<SearchMessagesQuery>,
+    auth: AuthUser,
+) -> ApiResult<Json<Vec<MessageJson>>> {
+    let messages = message::search_messages(&state.db, &auth.id, Some(&chat_id), &query).await?;
+    Ok(Json(messages))
+}
+
+async fn save_message(
     State(state): State<AppState>,
     Path(chat_id): Path<String>,
     auth: AuthUser,
     JsonExtractor(body): JsonExtractor<SaveMessageRequest>,
 ) -> ApiResult<Json<MessageSaveResponse>> {
-    let message =
-        message::save_direct_message(&state.db, &auth.id, &chat_id, &body.content).await?;
+    let message = message::save_message(
+        &state.db,
+        &auth.id,
+        &chat_id,
+        &body.content,
+        &body.reply_to,
+        &body.attachments,
+    )
+    .await?;
     let message_response = MessageSaveResponse {
-        id: message.id,
-        chat_id: message.chat_id,
-        author_id: message.author_id,
-        content: message.content,
-        timestamp: message.timestamp,
+        message,
         ack_id: body.ack_id,
     };
     ws::emit_new_message(&state, &message_response);
     Ok(Json(message_response))
 }
 
+async fn edit_message(
+    State(state): State<AppState>,
+    Path((chat_id, message_id)): Path<(String, String)>,
+    auth: AuthUser,
+    JsonExtractor(body): JsonExtractor<EditMessageRequest>,
+) -> ApiResult<Json<MessageJson>> {
+    let message = message::edit_message(
+        &state.db,
+        &auth.id,
+        &chat_id,
+        &message_id,
+        body.content.trim(),
+    )
+    .await?;
+    ws::emit_message_updated(&state, &message);
+    Ok(Json(message))
+}
+
+async fn delete_message(
+    State(state): State<AppState>,
+    Path((chat_id, message_id)): Path<(String, String)>,
+    auth: AuthUser,
+) -> ApiResult<Json<MessageDeleteResponse>> {
+    let result = message::delete_message(&state.db, &auth.id, &chat_id, &message_id).await?;
+    attachment::delete_attachment_files(state.storage.as_ref(), &result.attachment_ids).await;
+    ws::emit_message_deleted(&state, &result);
+    Ok(Json(result))
+}
+
+async fn upload_attachment(
+    State(state): State<AppState>,
+    Path(chat_id): Path<String>,
+    auth: AuthUser,
+    multipart: Result<Multipart, MultipartRejection>,
+) -> ApiResult<Json<MessageAttachment>> {
+    // checked before reading the body so unauthorized uploads are rejected early.
+    let (_, permissions) = chat::find_chat_with_permissions(&state.db, &chat_id, &auth.id).await?;
+    permissions.require(ChatPermissions::SEND_MESSAGES)?;
+
+    let (filename, content_type, data) = read_upload(&state.config, multipart).await?;
+
+    let attachment = attachment::create_attachment(
+        &state.db,
+        state.storage.as_ref(),
+        &auth.id,
+        &chat_id,
+        &filename,
+        &content_type,
+        &data,
+    )
+    .await?;
+
+    Ok(Json(attachment.into()))
+}
+
+/// reads the `file` field of an upload. the size and type are checked against the attachment limits.
+/// returns the filename, content type and contents of the file.
+pub async fn read_upload(
+    config: &ApiConfig,
+    multipart: Result<Multipart, MultipartRejection>,
+) -> ApiResult<(String, String, Vec<u8>)> {
+    let mut multipart = multipart.map_err(|_| ApiError::InvalidAttachment)?;
+    let mut field = loop {
+        match multipart.next_field().await {
+            Ok(Some(field)) if field.name() == Some("file") => break field,
+            Ok(Some(_)) => continue,
+            Ok(None) | Err(_) => return Err(ApiError::InvalidAttachment),
+        }
+    };
+
+    let filename = field
+        .file_name()
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty() && name.len() <= 255)
+        .ok_or(ApiError::InvalidAttachment)?;
+    let content_type = field
+        .content_type()
+        .map(|content_type| content_type.to_ascii_lowercase())
+        .ok_or(ApiError::UnsupportedAttachmentType)?;
+    if !config.attachment_mime_types.contains(&content_type) {
+        return Err(ApiError::UnsupportedAttachmentType);
+    }
+
+    let mut data = Vec::new();
+    while let Some(chunk) = field
+        .chunk()
+        .await
+        .map_err(|_| ApiError::InvalidAttachment)?
+    {
+        if data.len() + chunk.len() > config.max_attachment_size {
+            return Err(ApiError::AttachmentTooLarge);
+        }
+        data.extend_from_slice(&chunk);
+    }
+    if data.is_empty() {
+        return Err(ApiError::InvalidAttachment);
+    }
+
+    Ok((filename, content_type, data))
+}
+
+async fn get_attachment(
+    State(state): State<AppState>,
+    Path((chat_id, attachment_id)): Path<(String, String)>,
+    auth: AuthUser,
+) -> ApiResult<impl IntoResponse> {
+    let (attachment, data) = attachment::get_attachment(
+        &state.db,
+        state.storage.as_ref(),
+        &auth.id,
+        &chat_id,
+        &attachment_id,
+    )
+    .await?;
+
+    let disposition = if attachment.content_type.starts_with("image/") {
+        "inline"
+    } else {
+        "attachment"
+    };
+    let filename: String = attachment
+        .filename
+        .chars()
+        .filter(|c| !c.is_control() && *c != '"' && *c != '\\')
+        .collect();
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, attachment.content_type),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("{disposition}; filename=\"{filename}\""),
+            ),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+        ],
+        data,
+    ))
+}
+
+async fn add_reaction(
+    State(state): State<AppState>,
+    Path((chat_id, message_id, emoji)): Path<(String, String, String)>,
+    auth: AuthUser,
+) -> ApiResult<Json<MessageReactionResponse>> {
+    validate_emoji(&emoji)?;
+    let result =
+        message::set_reaction(&state.db, &auth.id, &chat_id, &message_id, &emoji, true).await?;
+    ws::emit_message_reaction(&state, &result);
+    Ok(Json(result))
+}
+
+async fn remove_reaction(
+    State(state): State<AppState>,
+    Path((chat_id, message_id, emoji)): Path<(String, String, String)>,
+    auth: AuthUser,
+) -> ApiResult<Json<MessageReactionResponse>> {
+    validate_emoji(&emoji)?;
+    let result =
+        message::set_reaction(&state.db, &auth.id, &chat_id, &message_id, &emoji, false).await?;
+    ws::emit_message_reaction(&state, &result);
+    Ok(Json(result))
+}
+
+/// reactions are either a single emoji, including modifiers and zwj sequences, or a shortcode.
+fn validate_emoji(emoji: &str) -> ApiResult<()> {
+    if emoji.is_empty() || emoji.len() > 32 {
+        return Err(ApiError::InvalidReaction);
+    }
+    if REACTION_SHORTCODE_REGEX.is_match(emoji) {
+        return Ok(());
+    }
+
+    let is_emoji_sequence = emoji
+        .chars()
+        .all(|c| is_emoji_char(c) || is_emoji_component(c))
+        && emoji.chars().any(|c| is_emoji_char(c) || c == '\u{20e3}');
+    if !is_emoji_sequence {
+        return Err(ApiError::InvalidReaction);
+    }
+    Ok(())
+}
+
+fn is_emoji_char(c: char) -> bool {
+    matches!(
+        c as u32,
+        0x00a9
+            | 0x00ae
+            | 0x203c
+            | 0x2049
+            | 0x2122
+            | 0x2139
+            | 0x2194..=0x21aa
+            | 0x2300..=0x23ff
+            | 0x24c2
+            | 0x25aa..=0x25fe
+            | 0x2600..=0x27bf
+            | 0x2934..=0x2935
+            | 0x2b00..=0x2bff
+            | 0x3030
+            | 0x303d
+            | 0x3297
+            | 0x3299
+            | 0x1f000..=0x1faff
+    )
+}
+
+/// characters that only modify or join emojis, skin tones are part of `is_emoji_char`.
+fn is_emoji_component(c: char) -> bool {
+    matches!(
+        c,
+        '\u{200d}' | '\u{fe0f}' | '\u{20e3}' | '\u{e0020}'..='\u{e007f}' | '0'..='9' | '#' | '*'
+    )
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct MessageJson {
@@ -72,34 +435,166 @@ pub struct MessageJson {
     pub author_id: String,
     pub content: String,
     pub timestamp: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub edited_at: Option<u64>,
+    /// deleted messages are kept as tombstones with empty content.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deleted: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reactions: Vec<ReactionJson>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<MessageReplyJson>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<MessageAttachment>,
+}
+
+/// a small preview of the message being replied to.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageReplyJson {
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author_id: Option<String>,
+    /// the first few characters of the replied message.
+    pub content: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deleted: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ReactionJson {
+    pub emoji: String,
+    pub count: usize,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageReactionResponse {
+    pub chat_id: String,
+    pub message_id: String,
+    pub user_id: String,
+    pub emoji: String,
+    /// false when the reaction was removed.
+    pub added: bool,
+    /// number of users that reacted with `emoji` after this change.
+    pub count: usize,
 }
 
 #[derive(Deserialize, Serialize, Validate)]
 #[serde(rename_all = "camelCase")]
+#[validate(schema(function = "validate_save_message_request"))]
 pub struct SaveMessageRequest {
-    #[validate(length(
-        min = 1,
-        max = 1024,
-        message = "Must be between 1 and 1024 characters long."
-    ))]
+    #[serde(default)]
+    #[validate(length(max = 1024, message = "Must be atmost 1024 characters long."))]
     pub content: String,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     #[validate(length(equal = 26, message = "Invalid id."))]
     ack_id: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[validate(length(equal = 26, message = "Invalid id."))]
+    pub reply_to: Option<String>,
+
+    /// ids returned by the attachment upload endpoint.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[validate(length(max = 10, message = "Must have atmost 10 attachments."))]
+    pub attachments: Vec<String>,
+}
+
+fn validate_save_message_request(
+    body: &SaveMessageRequest,
+) -> Result<(), validator::ValidationError> {
+    if body.content.trim().is_empty() && body.attachments.is_empty() {
+        let mut error = validator::ValidationError::new("empty_message");
+        error.message = Some("Must have content or attachments.".into());
+        return Err(error);
+    }
+    Ok(())
 }
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
-pub struct MessageSaveResponse {
+pub struct MessageDeleteResponse {
     pub id: String,
     pub chat_id: String,
-    pub author_id: String,
+    /// the chat's last message after the deletion.
+    pub last_message_id: Option<String>,
+    #[serde(skip)]
+    pub attachment_ids: Vec<String>,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct EditMessageRequest {
+    #[validate(length(
+        min = 1,
+        max = 1024,
+        message = "Must be between 1 and 1024 characters long."
+    ))]
+    #[validate(custom = "validate_edited_content")]
     pub content: String,
-    pub timestamp: u64,
+}
+
+/// edited content is trimmed before it's saved, so it must not consist of whitespace only.
+fn validate_edited_content(content: &str) -> Result<(), validator::ValidationError> {
+    if content.trim().is_empty() {
+        let mut error = validator::ValidationError::new("empty_message");
+        error.message = Some("Must not be empty.".into());
+        return Err(error);
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageSaveResponse {
+    #[serde(flatten)]
+    pub message: MessageJson,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub ack_id: Option<String>,
 }
 
+#[derive(Deserialize, Validate)]
+pub struct CreateGroupChatRequest {
+    #[validate(length(
+        min = 1,
+        max = 100,
+        message = "Must be between 1 and 100 characters long."
+    ))]
+    #[validate(custom = "validate_group_chat_name")]
+    pub name: String,
+    #[validate(length(min = 1, max = 50, message = "Must have between 1 and 50 users."))]
+    pub recipients: Vec<String>,
+}
+
+/// the name is trimmed before it's saved, so it must not consist of whitespace only.
+fn validate_group_chat_name(name: &str) -> Result<(), validator::ValidationError> {
+    if name.trim().is_empty() {
+        let mut error = validator::ValidationError::new("empty_name");
+        error.message = Some("Must not be empty.".into());
+        return Err(error);
+    }
+    Ok(())
+}
+
+#[derive(Deserialize, Validate)]
+pub struct SetRecipientRoleRequest {
+    pub role: ChatRole,
+}
+
+#[derive(Deserialize, Validate)]
+#[serde(rename_all = "camelCase")]
+pub struct TransferOwnershipRequest {
+    #[validate(length(equal = 26, message = "Invalid id."))]
+    pub user_id: String,
+}
+
+#[derive(Deserialize, Validate)]
+#[serde(rename_all = "camelCase")]
+pub struct MarkChatReadRequest {
+    #[validate(length(equal = 26, message = "Invalid id."))]
+    pub message_id: String,
+}
+
 #[derive(Deserialize, Validate)]
 pub struct GetMessagesQuery {
     #[validate(length(equal = 26, message = "Invalid id."))]
@@ -109,3 +604,40 @@ pub struct GetMessagesQuery {
     #[validate(range(min = 1, max = 50, message = "Must be between 1 and 50."))]
     pub limit: Option<i64>,
 }
+
+#[derive(Deserialize, Validate)]
+#[serde(rename_all = "camelCase")]
+#[validate(schema(function = "validate_search_messages_query"))]
+pub struct SearchMessagesQuery {
+    #[validate(length(
+        min = 1,
+        max = 100,
+        message = "Must be between 1 and 100 characters long."
+    ))]
+    pub query: String,
+    #[validate(length(equal = 26, message = "Invalid id."))]
+    pub author_id: Option<String>,
+    /// unix timestamp in milliseconds. ulid timestamps only have 48 bits.
+    #[validate(range(max = 281474976710655_u64, message = "Invalid timestamp."))]
+    pub since: Option<u64>,
+    /// unix timestamp in milliseconds.
+    #[validate(range(max = 281474976710655_u64, message = "Invalid timestamp."))]
+    pub until: Option<u64>,
+    #[validate(length(equal = 26, message = "Invalid id."))]
+    pub before: Option<String>,
+    #[validate(range(min = 1, max = 50, message = "Must be between 1 and 50."))]
+    pub limit: Option<i64>,
+}
+
+fn validate_search_messages_query(
+    query: &SearchMessagesQuery,
+) -> Result<(), validator::ValidationError> {
+    if let (Some(since), Some(until)) = (query.since, query.until) {
+        if since > until {
+            let mut error = validator::ValidationError::new("invalid_range");
+            error.message = Some("Since must not be after until.".into());
+            return Err(error);
+        }
+    }
+    Ok(())
+}
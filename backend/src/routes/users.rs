@@ -2,23 +2,57 @@ use std::collections::HashMap;
 
 use axum::extract::{Path, Query};
 
-use axum::{extract::State, routing::put, Json, Router};
-use serde::Serialize;
+use axum::{
+    extract::{multipart::MultipartRejection, DefaultBodyLimit, Multipart, State},
+    response::IntoResponse,
+    routing::{delete, get, patch, post, put},
+    Json, Router,
+};
+use http::header;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::warn;
+use ulid::Ulid;
+use validator::Validate;
 
-use crate::database::models::chat::{Chat, ChatRecipient, ChatType};
+use crate::database::models::{
+    attachment::{self, MessageAttachment},
+    chat::{Chat, ChatRecipient, ChatType},
+};
 
-use crate::util::extractors::auth::AuthUser;
+use crate::util::{
+    constants::USERNAME_REGEX,
+    extractors::{auth::AuthUser, json::JsonExtractor, query::Query as QueryExtractor},
+    result::ApiError,
+};
 use crate::{app::AppState, util::result::ApiResult};
 
-use crate::database::models::user;
+use crate::database::models::user::{self, RelatedUserStatus, RelationStatus};
 
-use super::ws;
+use super::{chat::read_upload, ws};
 
 pub fn build_router() -> Router<AppState> {
-    Router::new().route(
-        "/:usernameOrId/friend",
-        put(add_friend).delete(remove_friend),
-    )
+    Router::new()
+        .route("/@me", delete(delete_account))
+        .route("/@me/deactivate", post(deactivate_account))
+        .route("/@me/username", put(change_username))
+        .route("/@me/relations", get(get_relations))
+        .route("/@me/profile", patch(update_profile))
+        .route(
+            "/@me/avatar",
+            // the size limit is enforced while reading the upload, see `chat::read_upload`.
+            put(upload_avatar)
+                .delete(delete_avatar)
+                .layer(DefaultBodyLimit::disable()),
+        )
+        .route("/:usernameOrId", get(get_user))
+        .route("/:usernameOrId/avatar", get(get_avatar))
+        .route(
+            "/:usernameOrId/friend",
+            put(add_friend).delete(remove_friend),
+        )
+        // blocking always takes an id, blocked users can deactivate and can't be found by name then.
+        .route("/:usernameOrId/block", put(block_user).delete(unblock_user))
 }
 
 async fn add_friend(
@@ -27,11 +61,8 @@ async fn add_friend(
     Query(params): Query<HashMap<String, String>>,
     auth: AuthUser,
 ) -> ApiResult<Json<AddFriendResponse>> {
-    let is_id = match params.get("type") {
-        Some(t) => t.to_ascii_lowercase() == "id",
-        None => false,
-    };
-    let result = user::add_friend(&state.db, &username_or_id, &auth.id, is_id).await?;
+    let result =
+        user::add_friend(&state.db, &username_or_id, &auth.id, is_id_lookup(&params)).await?;
 
     match result.chat {
         None => {
@@ -44,6 +75,28 @@ async fn add_friend(
     Ok(Json(result))
 }
 
+/// `/:usernameOrId` routes take a username unless `?type=id` is given.
+fn is_id_lookup(params: &HashMap<String, String>) -> bool {
+    match params.get("type") {
+        Some(t) => t.eq_ignore_ascii_case("id"),
+        None => false,
+    }
+}
+
+async fn resolve_user_id(
+    state: &AppState,
+    username_or_id: &str,
+    params: &HashMap<String, String>,
+) -> ApiResult<String> {
+    if is_id_lookup(params) {
+        return Ok(username_or_id.to_string());
+    }
+    let user = user::find_user_by_name(&state.db, username_or_id)
+        .await?
+        .ok_or(ApiError::UserNotFound)?;
+    Ok(user.account.id)
+}
+
 async fn remove_friend(
     State(state): State<AppState>,
     Path(user_id): Path<String>,
@@ -54,6 +107,306 @@ async fn remove_friend(
     Ok(Json(result))
 }
 
+async fn get_relations(
+    State(state): State<AppState>,
+    QueryExtractor(query): QueryExtractor<GetRelationsQuery>,
+    auth: AuthUser,
+) -> ApiResult<Json<Vec<RelatedUserStatus>>> {
+    let statuses = match query.status {
+        Some(ref status) => parse_status_filter(status).unwrap_or_default(),
+        None => vec![],
+    };
+    let relations = user::get_relations(
+        &state.db,
+        &auth.id,
+        &statuses,
+        &query.after,
+        query.limit.unwrap_or(100),
+        &state.sockets,
+    )
+    .await?;
+
+    Ok(Json(relations))
+}
+
+async fn get_user(
+    State(state): State<AppState>,
+    Path(username_or_id): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    auth: AuthUser,
+) -> ApiResult<Json<RelatedUserStatus>> {
+    let user_id = resolve_user_id(&state, &username_or_id, &params).await?;
+    let user = user::get_user(&state.db, &auth.id, &user_id, &state.sockets).await?;
+    Ok(Json(user))
+}
+
+async fn update_profile(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    JsonExtractor(body): JsonExtractor<UpdateProfileRequest>,
+) -> ApiResult<Json<Value>> {
+    let (profile, relations) = user::update_profile(
+        &state.db,
+        &auth.id,
+        &body.display_name,
+        &body.bio,
+        &body.status_text,
+    )
+    .await?;
+    ws::emit_profile_updated(&state, &auth.id, &profile, &relations);
+
+    let mut response = json!(profile);
+    response["id"] = json!(auth.id);
+    Ok(Json(response))
+}
+
+async fn upload_avatar(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    multipart: Result<Multipart, MultipartRejection>,
+) -> ApiResult<Json<MessageAttachment>> {
+    let (filename, content_type, data) = read_upload(&state.config, multipart).await?;
+    if !content_type.starts_with("image/") {
+        return Err(ApiError::UnsupportedAttachmentType);
+    }
+
+    let avatar = MessageAttachment {
+        id: Ulid::new().to_string(),
+        filename,
+        content_type,
+        size: data.len() as u64,
+    };
+    state.storage.put(&avatar.id, &data).await?;
+
+    let (previous, relations) = match user::set_avatar(&state.db, &auth.id, Some(&avatar)).await {
+        Ok(result) => result,
+        Err(err) => {
+            state.storage.delete(&avatar.id).await.ok();
+            return Err(err);
+        }
+    };
+
+    let profile = user::get_user(&state.db, &auth.id, &auth.id, &state.sockets)
+        .await?
+        .profile;
+    ws::emit_profile_updated(&state, &auth.id, &profile, &relations);
+
+    // the new avatar is saved at this point, failing to remove the old file is only logged.
+    if let Some(previous) = previous {
+        attachment::delete_attachment_files(state.storage.as_ref(), &[previous.id]).await;
+    }
+
+    Ok(Json(avatar))
+}
+
+async fn delete_avatar(
+    State(state): State<AppState>,
+    auth: AuthUser,
+) -> ApiResult<Json<Value>> {
+    let (previous, relations) = user::set_avatar(&state.db, &auth.id, None).await?;
+    let previous = previous.ok_or(ApiError::AttachmentNotFound)?;
+
+    let profile = user::get_user(&state.db, &auth.id, &auth.id, &state.sockets)
+        .await?
+        .profile;
+    ws::emit_profile_updated(&state, &auth.id, &profile, &relations);
+
+    attachment::delete_attachment_files(state.storage.as_ref(), &[previous.id]).await;
+
+    Ok(Json(json!({
+        "message": "Avatar removed."
+    })))
+}
+
+async fn get_avatar(
+    State(state): State<AppState>,
+    Path(username_or_id): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    auth: AuthUser,
+) -> ApiResult<impl IntoResponse> {
+    let user_id = resolve_user_id(&state, &username_or_id, &params).await?;
+    let avatar = user::get_user(&state.db, &auth.id, &user_id, &state.sockets)
+        .await?
+        .profile
+        .avatar
+        .ok_or(ApiError::AttachmentNotFound)?;
+    let data = state
+        .storage
+        .get(&avatar.id)
+        .await?
+        .ok_or(ApiError::AttachmentNotFound)?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, avatar.content_type),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+        ],
+        data,
+    ))
+}
+
+async fn block_user(
+    State(state): State<AppState>,
+    Path(user_id): Path<String>,
+    auth: AuthUser,
+) -> ApiResult<Json<Value>> {
+    let result = user::block_user(&state.db, &user_id, &auth.id).await?;
+    ws::emit_relation_updated(&state, &auth.id, &user_id, &result);
+
+    Ok(Json(json!({
+        "user": {
+            "id": user_id,
+            "relationship": result.status
+        },
+        "message": "User blocked."
+    })))
+}
+
+async fn unblock_user(
+    State(state): State<AppState>,
+    Path(user_id): Path<String>,
+    auth: AuthUser,
+) -> ApiResult<Json<Value>> {
+    let result = user::unblock_user(&state.db, &user_id, &auth.id).await?;
+    ws::emit_relation_updated(&state, &auth.id, &user_id, &result);
+
+    Ok(Json(json!({
+        "user": {
+            "id": user_id,
+            "relationship": result.status
+        },
+        "message": "User unblocked."
+    })))
+}
+
+async fn delete_account(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    JsonExtractor(body): JsonExtractor<ConfirmPasswordRequest>,
+) -> ApiResult<Json<Value>> {
+    let deleted =
+        user::delete_account(&state.db, &state.config, &auth.id, &body.password).await?;
+
+    // the account is gone at this point, cleanup failures are only logged.
+    if let Some(avatar) = deleted.avatar {
+        if let Err(err) = state.storage.delete(&avatar.id).await {
+            warn!("Failed to delete avatar {}: {:?}", avatar.id, err);
+        }
+    }
+    for result in &deleted.left_groups {
+        attachment::delete_attachment_files(state.storage.as_ref(), &result.attachment_ids).await;
+        ws::emit_group_recipient_removed(&state, &result.chat_id, &auth.id);
+        if let Some(ref new_owner_id) = result.new_owner_id {
+            ws::emit_group_owner_changed(&state, &result.chat_id, new_owner_id);
+        }
+    }
+
+    ws::emit_account_hidden(&state, &auth.id, &deleted.relations, true);
+    state.close_user_sockets(&auth.id);
+
+    Ok(Json(json!({
+        "message": "Successfully deleted account."
+    })))
+}
+
+async fn change_username(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    JsonExtractor(body): JsonExtractor<ChangeUsernameRequest>,
+) -> ApiResult<Json<Value>> {
+    let relations =
+        user::change_username(&state.db, &state.config, &auth.id, &body.username).await?;
+    ws::emit_username_changed(&state, &auth.id, &body.username, &relations);
+
+    Ok(Json(json!({
+        "id": auth.id,
+        "username": body.username
+    })))
+}
+
+async fn deactivate_account(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    JsonExtractor(body): JsonExtractor<ConfirmPasswordRequest>,
+) -> ApiResult<Json<Value>> {
+    let relations =
+        user::deactivate_account(&state.db, &state.config, &auth.id, &body.password).await?;
+
+    ws::emit_account_hidden(&state, &auth.id, &relations, false);
+    state.close_user_sockets(&auth.id);
+
+    Ok(Json(json!({
+        "message": "Successfully deactivated account. log in again to reactivate it."
+    })))
+}
+
+#[derive(Deserialize, Validate)]
+struct ChangeUsernameRequest {
+    #[validate(
+        length(
+            min = 3,
+            max = 32,
+            message = "Must be atleast 3 characters long and atmost 32 characters long."
+        ),
+        regex(
+            path = "USERNAME_REGEX",
+            message = "Must be made up of english alphabets, numbers, hyphens and underscores."
+        )
+    )]
+    username: String,
+}
+
+#[derive(Deserialize, Validate)]
+#[serde(rename_all = "camelCase")]
+struct UpdateProfileRequest {
+    /// fields that are left out are kept, empty strings clear them.
+    #[validate(length(max = 32, message = "Must be atmost 32 characters long."))]
+    display_name: Option<String>,
+    #[validate(length(max = 190, message = "Must be atmost 190 characters long."))]
+    bio: Option<String>,
+    #[validate(length(max = 128, message = "Must be atmost 128 characters long."))]
+    status_text: Option<String>,
+}
+
+#[derive(Deserialize, Validate)]
+struct GetRelationsQuery {
+    /// comma separated list of relationships to include.
+    #[validate(custom = "validate_status_filter")]
+    status: Option<String>,
+    #[validate(length(equal = 26, message = "Invalid id."))]
+    after: Option<String>,
+    #[validate(range(min = 1, max = 100, message = "Must be between 1 and 100."))]
+    limit: Option<usize>,
+}
+
+fn parse_status_filter(filter: &str) -> Option<Vec<RelationStatus>> {
+    filter
+        .split(',')
+        .map(|status| match status.trim() {
+            "Friend" => Some(RelationStatus::Friend),
+            "Incoming" => Some(RelationStatus::Incoming),
+            "Outgoing" => Some(RelationStatus::Outgoing),
+            "Blocked" => Some(RelationStatus::Blocked),
+            _ => None,
+        })
+        .collect()
+}
+
+fn validate_status_filter(filter: &str) -> Result<(), validator::ValidationError> {
+    if parse_status_filter(filter).is_none() {
+        let mut error = validator::ValidationError::new("invalid_status");
+        error.message =
+            Some("Must be a comma separated list of Friend, Incoming, Outgoing and Blocked.".into());
+        return Err(error);
+    }
+    Ok(())
+}
+
+#[derive(Deserialize, Validate)]
+struct ConfirmPasswordRequest {
+    password: String,
+}
+
 #[derive(Serialize)]
 pub struct AddFriendResponse {
     pub user: AddFriendUser,
@@ -76,16 +429,22 @@ pub struct RemoveFriendResponse {
 pub struct ChatJson {
     pub id: String,
     pub chat_type: ChatType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner_id: Option<String>,
     pub recipients: Vec<ChatRecipient>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub last_message_id: Option<String>,
 }
-// TODO: i dont remember why i need this. investigate.
+/// Chat is stored with an `_id` key, ChatJson is what gets sent to clients.
 impl From<Chat> for ChatJson {
     fn from(chat: Chat) -> Self {
         ChatJson {
             id: chat.id,
             chat_type: chat.chat_type,
+            name: chat.name,
+            owner_id: chat.owner_id,
             recipients: chat.recipients,
             last_message_id: chat.last_message_id,
         }
@@ -101,3 +460,35 @@ pub struct AddFriendUser {
 pub struct RemoveFriendUser {
     pub id: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_status_filter() {
+        assert_eq!(
+            parse_status_filter("Friend"),
+            Some(vec![RelationStatus::Friend])
+        );
+        assert_eq!(
+            parse_status_filter("Incoming, Outgoing,Blocked"),
+            Some(vec![
+                RelationStatus::Incoming,
+                RelationStatus::Outgoing,
+                RelationStatus::Blocked
+            ])
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_statuses() {
+        assert_eq!(parse_status_filter(""), None);
+        assert_eq!(parse_status_filter("friend"), None);
+        assert_eq!(parse_status_filter("Friend,"), None);
+        // users must not be able to list who blocked them.
+        assert_eq!(parse_status_filter("BlockedByOther"), None);
+        assert_eq!(parse_status_filter("None"), None);
+        assert!(validate_status_filter("Friend,Nope").is_err());
+    }
+}
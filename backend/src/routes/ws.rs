@@ -1,4 +1,8 @@
-use std::time::{Duration, UNIX_EPOCH};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant, UNIX_EPOCH},
+};
 
 use axum::{
     extract::{
@@ -11,31 +15,54 @@ use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use tokio::{
-    sync::mpsc::{self, UnboundedSender},
+    sync::{
+        mpsc::{self, UnboundedSender},
+        Notify,
+    },
     time::timeout,
 };
 use tracing::{debug, warn};
 
 use crate::{
-    app::AppState,
+    app::{AppState, SocketChannel},
     database::models::{
-        chat, message, session,
-        user::{self, RelatedUserStatus, Relation, RelationStatus},
+        chat::{self, ChatRole},
+        message, session,
+        user::{
+            self, Presence, ProfileDetails, RelatedUserStatus, Relation, RelationStatus,
+            RelationUpdate,
+        },
+    },
+    util::{
+        constants::{
+            DELETED_USER_NAME, LAST_SEEN_PERSIST_INTERVAL_S, TYPING_PERMISSION_CACHE_TTL_S,
+        },
+        permissions::{resolve_chat_permissions, ChatPermissions},
+        result::{ApiError, ApiResult},
     },
-    util::result::{ApiError, ApiResult},
 };
 
-use super::{chat::{MessageJson, MessageSaveResponse}, users::ChatJson};
+use super::{
+    chat::{MessageDeleteResponse, MessageJson, MessageReactionResponse, MessageSaveResponse},
+    users::ChatJson,
+};
 
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct ReadyData {
     id: String,
     username: String,
+    /// the presence the user picked, see `PresenceUpdate`.
+    presence: Presence,
     users: Vec<RelatedUserStatus>,
-    chats: Vec<chat::Chat>,
+    chats: Vec<ChatJson>,
     last_messages: Vec<crate::routes::chat::MessageJson>,
+    /// number of unread messages by chat id, chats without unread messages are left out.
+    unread_counts: HashMap<String, u64>,
     session_id: String,
+    /// ids of the chats the user can read, used to register the socket in them.
+    #[serde(skip)]
+    readable_chat_ids: Vec<String>,
 }
 
 pub async fn ws_handler(ws: WebSocketUpgrade, State(state): State<AppState>) -> impl IntoResponse {
@@ -101,7 +128,7 @@ async fn handle_socket(mut socket: WebSocket, state: AppState) {
 }
 
 async fn prepare_ready_data(state: &AppState, token: &str) -> ApiResult<ReadyData> {
-    let (session_id, user) = session::get_user_from_token(&state.db, token).await?; // TODO: check for UnauthorizedError
+    let (session_id, user) = session::get_user_from_token(&state.db, &state.config, token).await?; // TODO: check for UnauthorizedError
     let chats = chat::get_chats_of_user(&state.db, &user.account.id).await?;
 
     let mut last_message_ids: Vec<&str> = vec![];
@@ -123,13 +150,23 @@ async fn prepare_ready_data(state: &AppState, token: &str) -> ApiResult<ReadyDat
         }
     }
 
+    let empty_vec: Vec<Relation> = vec![];
+    let relations = if let Some(ref profile) = user.profile {
+        &profile.relations
+    } else {
+        &empty_vec
+    };
+
+    let readable_chat_ids: Vec<String> = chats
+        .iter()
+        .filter(|chat| {
+            resolve_chat_permissions(chat, &user.account.id, relations)
+                .contains(ChatPermissions::READ_MESSAGES)
+        })
+        .map(|chat| chat.id.to_owned())
+        .collect();
+
     let related_users = if !related_user_ids.is_empty() {
-        let empty_vec: Vec<Relation> = vec![];
-        let relations = if let Some(ref profile) = user.profile {
-            &profile.relations
-        } else {
-            &empty_vec
-        };
         user::find_related_users_with_status(
             &state.db,
             &related_user_ids,
@@ -147,13 +184,23 @@ async fn prepare_ready_data(state: &AppState, token: &str) -> ApiResult<ReadyDat
         vec![]
     };
 
+    let readable_chats: Vec<&chat::Chat> = chats
+        .iter()
+        .filter(|chat| readable_chat_ids.contains(&chat.id))
+        .collect();
+    let unread_counts =
+        message::get_unread_counts(&state.db, &user.account.id, &readable_chats).await?;
+
     Ok(ReadyData {
         id: user.account.id,
         username: user.account.username,
+        presence: user.account.presence,
         users: related_users,
-        chats,
+        chats: chats.into_iter().map(ChatJson::from).collect(),
         last_messages,
+        unread_counts,
         session_id,
+        readable_chat_ids,
     })
 }
 
@@ -166,7 +213,9 @@ async fn setup_user_socket(
     debug!("Client authenticated: {}", &data.id);
 
     let (tx, mut rx) = mpsc::unbounded_channel();
+    let close = Arc::new(Notify::new());
     let mut was_offline = false;
+    let presence;
     let friend_ids: Vec<String> = data
         .users
         .iter()
@@ -178,16 +227,7 @@ async fn setup_user_socket(
             }
         })
         .collect();
-    let chat_ids = data
-        .chats
-        .iter()
-        .filter(|chat| {
-            chat.recipients
-                .iter()
-                .any(|recipient| friend_ids.contains(&recipient.id))
-        })
-        .map(|chat| chat.id.to_owned())
-        .collect::<Vec<String>>();
+    let chat_ids = data.readable_chat_ids.clone();
 
     // database is the single source of truth. updating local state in case database was manually updated.
     for id in &chat_ids {
@@ -207,8 +247,16 @@ async fn setup_user_socket(
         // Replacing it in case database was manually updated.
         user_socket.chats = chat_ids.clone();
         user_socket.online = true;
-        user_socket.last_seen_s = None;
-        user_socket.channel.push(tx.clone());
+        user_socket.presence = data.presence;
+        presence = user_socket.visible_presence();
+        if presence.is_some() {
+            user_socket.last_seen_s = None;
+        }
+        user_socket.channel.push(SocketChannel {
+            sender: tx.clone(),
+            session_id: data.session_id.to_owned(),
+            close: close.clone(),
+        });
 
         old_chat_ids
     };
@@ -231,45 +279,92 @@ async fn setup_user_socket(
         }
     });
 
-    if was_offline {
-        state.emit_user_online(&data.id, &friend_ids, true, None);
+    if was_offline && presence.is_some() {
+        state.emit_user_online(&data.id, &friend_ids, presence, None);
     }
 
+    let mut typing_permissions = HashMap::new();
+
     // this is just an example of how to send data to the client.
-    while let Some(msg) = stream.next().await {
+    while let Some(msg) = tokio::select! {
+        msg = stream.next() => msg,
+        _ = close.notified() => {
+            tx.send(json!({ "event": "SessionRevoked", "data": null }).to_string())
+                .ok();
+            None
+        }
+    } {
         if let Ok(Message::Text(text)) = msg {
             match serde_json::from_str::<WsInput>(&text) {
                 Ok(input) => match input.event.as_str() {
-                    "ChatStartTyping" => {
-                        if state.user_perm_chat_exists(&data.id, &input.data) {
+                    "ChatStartTyping" | "ChatEndTyping" => {
+                        let chat_id = input.data.as_str().unwrap_or_default();
+                        if can_type(state, &data.id, chat_id, &mut typing_permissions).await {
                             state.emit_chat_data_except(
-                                &input.data,
+                                chat_id,
                                 json!({
-                                    "event": "ChatStartTyping",
+                                    "event": input.event,
                                     "data": {
-                                        "chatId": input.data,
+                                        "chatId": chat_id,
                                         "userId": data.id
                                     }
                                 }),
-                                &data.id
+                                &data.id,
                             );
                         }
                     }
-                    "ChatEndTyping" => {
-                        if state.user_perm_chat_exists(&data.id, &input.data) {
-                            state.emit_chat_data_except(
-                                &input.data,
-                                json!({
-                                    "event": "ChatEndTyping",
-                                    "data": {
-                                        "chatId": input.data,
-                                        "userId": data.id
-                                    }
-                                }),
-                                &data.id
-                            );
+                    "ChatRead" => match serde_json::from_value::<ChatReadInput>(input.data) {
+                        Ok(read) => {
+                            match chat::mark_chat_read(
+                                &state.db,
+                                &data.id,
+                                &read.chat_id,
+                                &read.message_id,
+                            )
+                            .await
+                            {
+                                Ok(true) => {
+                                    emit_chat_read(state, &read.chat_id, &data.id, &read.message_id)
+                                }
+                                Ok(false) => {}
+                                Err(err) => {
+                                    tx.send(
+                                        json!({ "event": "Error", "data": err.error_description() })
+                                            .to_string(),
+                                    )
+                                    .unwrap();
+                                }
+                            }
                         }
-                    }
+                        Err(_) => {
+                            tx.send(
+                                json!({ "event": "Error", "data": "Invalid json data." })
+                                    .to_string(),
+                            )
+                            .unwrap();
+                        }
+                    },
+                    "PresenceUpdate" => match serde_json::from_value::<Presence>(input.data) {
+                        Ok(presence) => {
+                            match user::set_presence(&state.db, &data.id, presence).await {
+                                Ok(()) => update_presence(state, &data.id, presence).await,
+                                Err(err) => {
+                                    tx.send(
+                                        json!({ "event": "Error", "data": err.error_description() })
+                                            .to_string(),
+                                    )
+                                    .unwrap();
+                                }
+                            }
+                        }
+                        Err(_) => {
+                            tx.send(
+                                json!({ "event": "Error", "data": "Invalid json data." })
+                                    .to_string(),
+                            )
+                            .unwrap();
+                        }
+                    },
                     "Ping" => {
                         tx.send(json!({ "event": "Pong", "data": input.data }).to_string())
                             .unwrap();
@@ -288,28 +383,69 @@ async fn setup_user_socket(
     tx
 }
 
+async fn has_chat_permissions(
+    state: &AppState,
+    user_id: &str,
+    chat_id: &str,
+    permissions: ChatPermissions,
+) -> bool {
+    match chat::find_chat_with_permissions(&state.db, chat_id, user_id).await {
+        Ok((_, chat_permissions)) => chat_permissions.contains(permissions),
+        Err(_) => false,
+    }
+}
+
+/// typing events are frequent, so membership is checked in memory and the send permission is cached
+/// per socket for a while instead of hitting the database on every event.
+async fn can_type(
+    state: &AppState,
+    user_id: &str,
+    chat_id: &str,
+    cache: &mut HashMap<String, (bool, Instant)>,
+) -> bool {
+    let is_member = state
+        .chats
+        .get(chat_id)
+        .is_some_and(|users| users.iter().any(|id| id == user_id));
+    if !is_member {
+        return false;
+    }
+
+    if let Some((allowed, checked_at)) = cache.get(chat_id) {
+        if checked_at.elapsed() < Duration::from_secs(TYPING_PERMISSION_CACHE_TTL_S) {
+            return *allowed;
+        }
+    }
+    let allowed =
+        has_chat_permissions(state, user_id, chat_id, ChatPermissions::SEND_MESSAGES).await;
+    cache.insert(chat_id.to_owned(), (allowed, Instant::now()));
+    allowed
+}
+
 async fn handle_disconnect(state: &AppState, user_id: &str, tx: UnboundedSender<String>) {
     // making a copy because we dont want to keep the sockets dashmap locked for long.
 
-    let (has_no_clients, user_chats, last_seen_s) = {
+    let (has_no_clients, user_chats, was_visible, last_seen_s) = {
         // unwrapping it because this is impossible. data was inserted before this code. (unless the hashmap was modified somewhere else???)
 
         let mut user_socket = state.sockets.get_mut(user_id).unwrap();
         let old_chats = user_socket.chats.to_owned();
-        user_socket.channel.retain(|c| !tx.same_channel(c));
-        let mut last_seen_s = None;
+        let was_visible = user_socket.visible_presence().is_some();
+        user_socket.channel.retain(|c| !tx.same_channel(&c.sender));
         if user_socket.channel.is_empty() {
-            last_seen_s = Some(
-                std::time::SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .expect("TIME TRAVEL>!!?!?!")
-                    .as_secs(),
-            );
             user_socket.chats = vec![]; // to free the ram right away. (clear() function does not free the ram.)
             user_socket.online = false;
-            user_socket.last_seen_s = last_seen_s;
+            // invisible users already appear offline since they went invisible.
+            if was_visible {
+                user_socket.last_seen_s = Some(unix_time_s());
+            }
         }
-        (user_socket.channel.is_empty(), old_chats, last_seen_s)
+        (
+            user_socket.channel.is_empty(),
+            old_chats,
+            was_visible,
+            user_socket.last_seen_s,
+        )
     };
     if has_no_clients {
         // Collect the chats that are to be removed after cleaning up the users
@@ -332,8 +468,15 @@ async fn handle_disconnect(state: &AppState, user_id: &str, tx: UnboundedSender<
             state.chats.remove(chat);
         }
 
-        let friend_ids = user::get_friend_ids(&state.db, user_id).await.unwrap();
-        state.emit_user_online(user_id, &friend_ids, false, last_seen_s);
+        if was_visible {
+            if let Some(last_seen_s) = last_seen_s {
+                if let Err(err) = user::set_last_seen(&state.db, user_id, last_seen_s).await {
+                    warn!("Failed to save last seen of {user_id}: {:?}", err);
+                }
+            }
+            let friend_ids = user::get_friend_ids(&state.db, user_id).await.unwrap();
+            state.emit_user_online(user_id, &friend_ids, None, last_seen_s);
+        }
     }
 
     debug!(
@@ -342,10 +485,79 @@ async fn handle_disconnect(state: &AppState, user_id: &str, tx: UnboundedSender<
     )
 }
 
+/// applies the presence the user picked and tells friends if what they see changed.
+async fn update_presence(state: &AppState, user_id: &str, presence: Presence) {
+    let (previous, current, last_seen_s) = {
+        let Some(mut user_socket) = state.sockets.get_mut(user_id) else {
+            return;
+        };
+        let previous = user_socket.visible_presence();
+        user_socket.presence = presence;
+        let current = user_socket.visible_presence();
+        if current.is_some() {
+            user_socket.last_seen_s = None;
+        } else if previous.is_some() {
+            user_socket.last_seen_s = Some(unix_time_s());
+        }
+
+        // the user's own clients get the presence they picked, not the visible one.
+        user_socket.send_json(&json!({
+            "event": "UserUpdate",
+            "data": {
+                "user": {
+                    "id": user_id,
+                    "presence": presence
+                }
+            }
+        }));
+        (previous, current, user_socket.last_seen_s)
+    };
+
+    if previous != current {
+        if let Some(last_seen_s) = last_seen_s {
+            if let Err(err) = user::set_last_seen(&state.db, user_id, last_seen_s).await {
+                warn!("Failed to save last seen of {user_id}: {:?}", err);
+            }
+        }
+        match user::get_friend_ids(&state.db, user_id).await {
+            Ok(friend_ids) => state.emit_user_online(user_id, &friend_ids, current, last_seen_s),
+            Err(err) => warn!("Failed to get friends of {user_id}: {:?}", err),
+        }
+    }
+}
+
+/// periodically stores the current time as last seen time of visible online users,
+/// so it isn't lost when the server stops without them disconnecting.
+pub async fn persist_last_seen(state: AppState) {
+    let mut interval = tokio::time::interval(Duration::from_secs(LAST_SEEN_PERSIST_INTERVAL_S));
+    loop {
+        interval.tick().await;
+        let user_ids: Vec<String> = state
+            .sockets
+            .iter()
+            .filter(|user_socket| user_socket.visible_presence().is_some())
+            .map(|user_socket| user_socket.key().to_owned())
+            .collect();
+        if user_ids.is_empty() {
+            continue;
+        }
+        if let Err(err) = user::set_last_seen_many(&state.db, &user_ids, unix_time_s()).await {
+            warn!("Failed to persist last seen: {:?}", err);
+        }
+    }
+}
+
+fn unix_time_s() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("TIME TRAVEL>!!?!?!")
+        .as_secs()
+}
+
 pub fn emit_new_message(state: &AppState, message: &MessageSaveResponse) {
     state.emit_chat_data(
         //TODO: make it so that the sender socket doesn't receive the message.
-        &message.chat_id,
+        &message.message.chat_id,
         json!({
             "event": "ChatNewMessage",
             "data": message
@@ -353,6 +565,50 @@ pub fn emit_new_message(state: &AppState, message: &MessageSaveResponse) {
     );
 }
 
+pub fn emit_message_updated(state: &AppState, message: &MessageJson) {
+    state.emit_chat_data(
+        &message.chat_id,
+        json!({
+            "event": "ChatMessageUpdate",
+            "data": message
+        }),
+    );
+}
+
+pub fn emit_message_deleted(state: &AppState, message: &MessageDeleteResponse) {
+    state.emit_chat_data(
+        &message.chat_id,
+        json!({
+            "event": "ChatMessageDelete",
+            "data": message
+        }),
+    );
+}
+
+pub fn emit_message_reaction(state: &AppState, reaction: &MessageReactionResponse) {
+    state.emit_chat_data(
+        &reaction.chat_id,
+        json!({
+            "event": "ChatMessageReaction",
+            "data": reaction
+        }),
+    );
+}
+
+pub fn emit_chat_read(state: &AppState, chat_id: &str, user_id: &str, message_id: &str) {
+    state.emit_chat_data(
+        chat_id,
+        json!({
+            "event": "ChatRead",
+            "data": {
+                "chatId": chat_id,
+                "userId": user_id,
+                "messageId": message_id
+            }
+        }),
+    );
+}
+
 // TODO: Test this
 pub fn leave_direct_chat(state: &AppState, users: &[&str], chat_id: &str) {
     for user_id in users {
@@ -375,6 +631,124 @@ pub fn emit_new_direct_chat_join(state: &AppState, users: Vec<String>, chat: &Ch
 
 }
 
+/// registers the chat for every given user that is currently connected.
+pub fn join_chat(state: &AppState, users: &[String], chat_id: &str) {
+    for user_id in users {
+        // the socket guard is dropped before touching `state.chats` to keep lock order consistent.
+        let joined = match state.sockets.get_mut(user_id) {
+            Some(mut user) if user.online => {
+                if !user.chats.iter().any(|id| id == chat_id) {
+                    user.chats.push(chat_id.to_owned());
+                }
+                true
+            }
+            _ => false,
+        };
+        if joined {
+            let mut chat_users = state.chats.entry(chat_id.to_owned()).or_default();
+            if !chat_users.contains(user_id) {
+                chat_users.push(user_id.to_owned());
+            }
+        }
+    }
+}
+
+pub fn emit_new_group_chat(state: &AppState, chat: &ChatJson) {
+    let users: Vec<String> = chat.recipients.iter().map(|r| r.id.to_owned()).collect();
+    join_chat(state, &users, &chat.id);
+    state.emit_chat_data(
+        &chat.id,
+        json!({
+            "event": "ChatNew",
+            "data": chat
+        }),
+    );
+}
+
+/// unregisters the chat for the given user.
+pub fn leave_chat(state: &AppState, user_id: &str, chat_id: &str) {
+    if let Some(mut user) = state.sockets.get_mut(user_id) {
+        user.chats.retain(|id| id != chat_id);
+    }
+    let is_empty = match state.chats.get_mut(chat_id) {
+        Some(mut users) => {
+            users.retain(|id| id != user_id);
+            users.is_empty()
+        }
+        None => false,
+    };
+    if is_empty {
+        state.chats.remove(chat_id);
+    }
+}
+
+pub fn emit_group_recipient_added(state: &AppState, chat: &ChatJson, user_id: &str) {
+    state.emit_chat_data(
+        &chat.id,
+        json!({
+            "event": "ChatRecipientAdd",
+            "data": {
+                "chatId": chat.id,
+                "userId": user_id
+            }
+        }),
+    );
+    join_chat(state, &[user_id.to_owned()], &chat.id);
+    if let Some(user) = state.sockets.get(user_id) {
+        user.send_json(&json!({
+            "event": "ChatNew",
+            "data": chat
+        }));
+    }
+}
+
+pub fn emit_group_recipient_removed(state: &AppState, chat_id: &str, user_id: &str) {
+    // sent before leaving so the removed user also gets notified.
+    state.emit_chat_data(
+        chat_id,
+        json!({
+            "event": "ChatRecipientRemove",
+            "data": {
+                "chatId": chat_id,
+                "userId": user_id
+            }
+        }),
+    );
+    leave_chat(state, user_id, chat_id);
+}
+
+pub fn emit_group_recipient_role_changed(
+    state: &AppState,
+    chat_id: &str,
+    user_id: &str,
+    role: &ChatRole,
+) {
+    state.emit_chat_data(
+        chat_id,
+        json!({
+            "event": "ChatRecipientUpdate",
+            "data": {
+                "chatId": chat_id,
+                "userId": user_id,
+                "role": role
+            }
+        }),
+    );
+}
+
+pub fn emit_group_owner_changed(state: &AppState, chat_id: &str, owner_id: &str) {
+    state.emit_chat_data(
+        chat_id,
+        json!({
+            "event": "ChatOwnerUpdate",
+            "data": {
+                "chatId": chat_id,
+                "ownerId": owner_id
+            }
+        }),
+    );
+}
+
 pub fn emit_friend_added(state: &AppState, user_id: &str, receiver_user_id: &str, chat: &ChatJson) {
     if let Some(user) = state.sockets.get(user_id) {
         match state.sockets.get(receiver_user_id) {
@@ -385,7 +759,8 @@ pub fn emit_friend_added(state: &AppState, user_id: &str, receiver_user_id: &str
                        "user": {
                         "id": receiver_user_id,
                         "relationship": RelationStatus::Friend,
-                        "online": receiver_user.online,
+                        "online": receiver_user.visible_presence().is_some(),
+                        "presence": receiver_user.visible_presence(),
                         "lastSeen": receiver_user.last_seen_s
                        },
                     }
@@ -414,7 +789,8 @@ pub fn emit_friend_added(state: &AppState, user_id: &str, receiver_user_id: &str
                        "user": {
                         "id": user_id,
                         "relationship": RelationStatus::Friend,
-                        "online": user.online,
+                        "online": user.visible_presence().is_some(),
+                        "presence": user.visible_presence(),
                         "lastSeen": user.last_seen_s
                        },
                     }
@@ -508,7 +884,145 @@ pub fn emit_friend_removed(
         }));
     };
 }
+/// sends the new relationship to both users. they are no longer friends, so both appear offline.
+pub fn emit_relation_updated(
+    state: &AppState,
+    user_id: &str,
+    receiver_user_id: &str,
+    update: &RelationUpdate,
+) {
+    if let Some(id) = &update.chat_id {
+        leave_direct_chat(state, &[user_id, receiver_user_id], id)
+    }
+
+    if let Some(user) = state.sockets.get(user_id) {
+        user.send_json(&json!({
+            "event": "UserUpdate",
+            "data": {
+                "user": {
+                    "id": receiver_user_id,
+                    "relationship": update.status,
+                    "online": false
+                }
+            }
+        }));
+    }
+
+    if let Some(user) = state.sockets.get(receiver_user_id) {
+        user.send_json(&json!({
+            "event": "UserUpdate",
+            "data": {
+                "user": {
+                    "id": user_id,
+                    "relationship": update.receiver_status,
+                    "online": false
+                }
+            }
+        }));
+    }
+}
+
+/// sends the new username to related users and the user's other clients.
+pub fn emit_username_changed(
+    state: &AppState,
+    user_id: &str,
+    username: &str,
+    relations: &[Relation],
+) {
+    let data = json!({
+        "event": "UserUpdate",
+        "data": {
+            "user": {
+                "id": user_id,
+                "username": username
+            }
+        }
+    });
+
+    for id in relations
+        .iter()
+        .map(|relation| relation.id.as_str())
+        .chain([user_id])
+    {
+        if let Some(socket) = state.sockets.get(id) {
+            socket.send_json(&data);
+        }
+    }
+}
+
+/// sends the new profile to the user's other clients and related users that aren't blocked.
+pub fn emit_profile_updated(
+    state: &AppState,
+    user_id: &str,
+    profile: &ProfileDetails,
+    relations: &[Relation],
+) {
+    let mut user = json!(profile);
+    user["id"] = json!(user_id);
+    let data = json!({
+        "event": "UserUpdate",
+        "data": {
+            "user": user
+        }
+    });
+
+    for id in relations
+        .iter()
+        .filter(|relation| {
+            !matches!(
+                relation.status,
+                RelationStatus::Blocked | RelationStatus::BlockedByOther
+            )
+        })
+        .map(|relation| relation.id.as_str())
+        .chain([user_id])
+    {
+        if let Some(socket) = state.sockets.get(id) {
+            socket.send_json(&data);
+        }
+    }
+}
+
+/// tells related users that the account is gone. relations are only dropped if it was deleted.
+pub fn emit_account_hidden(state: &AppState, user_id: &str, relations: &[Relation], deleted: bool) {
+    let mut user = json!({
+        "id": user_id,
+        "username": DELETED_USER_NAME,
+        "online": false
+    });
+    if deleted {
+        user["relationship"] = json!(RelationStatus::None);
+    }
+    let data = json!({
+        "event": "UserUpdate",
+        "data": {
+            "user": user
+        }
+    });
+
+    for relation in relations {
+        if let Some(socket) = state.sockets.get(&relation.id) {
+            socket.send_json(&data);
+        }
+    }
+}
+
 impl AppState {
+    /// closes the websockets that were opened with any of the revoked sessions.
+    pub fn close_sessions(&self, user_id: &str, session_ids: &[String]) {
+        if let Some(socket) = self.sockets.get(user_id) {
+            socket.close_sessions(session_ids);
+        }
+    }
+
+    /// closes every websocket of the user.
+    pub fn close_user_sockets(&self, user_id: &str) {
+        if let Some(socket) = self.sockets.get(user_id) {
+            for channel in &socket.channel {
+                channel.close.notify_one();
+            }
+        }
+    }
     pub fn emit_chat_data(&self, chat_id: &str, data: serde_json::Value) {
         if let Some(users) = self.chats.get(chat_id) {
             for user_id in users.iter() {
@@ -529,18 +1043,11 @@ impl AppState {
             }
         }
     }
-    pub fn user_perm_chat_exists(&self, user_id: &str, chat_id: &str) -> bool {
-        if let Some(user) = self.sockets.get(user_id) {
-            user.chats.contains(&chat_id.to_owned())
-        } else {
-            false
-        }
-    }
     pub fn emit_user_online(
         &self,
         user_id: &str,
         recipients: &Vec<String>,
-        is_online: bool,
+        presence: Option<Presence>,
         last_seen_s: Option<u64>,
     ) {
         for recipient in recipients {
@@ -550,7 +1057,8 @@ impl AppState {
                     "data": {
                         "user": {
                             "id": user_id,
-                            "online": is_online,
+                            "online": presence.is_some(),
+                            "presence": presence,
                             "lastSeen": last_seen_s
                         }
                     }
@@ -570,5 +1078,12 @@ struct AuthReq {
 #[derive(Deserialize)]
 struct WsInput {
     event: String,
-    data: String,
+    data: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ChatReadInput {
+    chat_id: String,
+    message_id: String,
 }
@@ -0,0 +1,52 @@
+use std::{io::ErrorKind, path::PathBuf};
+
+use anyhow::{Context, Result};
+use axum::async_trait;
+use tokio::fs;
+
+use super::Storage;
+
+/// stores files in a directory on the local filesystem.
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    // ids are generated by the server so they are safe to use as file names.
+    fn path(&self, id: &str) -> PathBuf {
+        self.root.join(id)
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, id: &str, data: &[u8]) -> Result<()> {
+        fs::create_dir_all(&self.root)
+            .await
+            .context("LocalStorage::put: Failed to create upload directory.")?;
+        fs::write(self.path(id), data)
+            .await
+            .context("LocalStorage::put: Failed to write file.")?;
+        Ok(())
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<Vec<u8>>> {
+        match fs::read(self.path(id)).await {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).context("LocalStorage::get: Failed to read file."),
+        }
+    }
+
+    async fn delete(&self, id: &str) -> Result<()> {
+        match fs::remove_file(self.path(id)).await {
+            Ok(_) => Ok(()),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err).context("LocalStorage::delete: Failed to remove file."),
+        }
+    }
+}
@@ -0,0 +1,13 @@
+pub mod local;
+
+use anyhow::Result;
+use axum::async_trait;
+
+/// Storage is where the contents of uploaded files live. metadata is kept in the database.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn put(&self, id: &str, data: &[u8]) -> Result<()>;
+    /// returns `None` if nothing is stored under `id`.
+    async fn get(&self, id: &str) -> Result<Option<Vec<u8>>>;
+    async fn delete(&self, id: &str) -> Result<()>;
+}
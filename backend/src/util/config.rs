@@ -1,16 +1,58 @@
-use super::constants::{API_DEFAULT_HOST, API_DEFAULT_PORT};
-use anyhow::{Context, Result};
+use super::constants::{
+    API_DEFAULT_ATTACHMENT_MIME_TYPES, API_DEFAULT_HOST, API_DEFAULT_LOGIN_BASE_LOCKOUT,
+    API_DEFAULT_LOGIN_MAX_ATTEMPTS_PER_IP, API_DEFAULT_LOGIN_MAX_ATTEMPTS_PER_USERNAME,
+    API_DEFAULT_LOGIN_MAX_LOCKOUT, API_DEFAULT_MAX_ATTACHMENT_SIZE, API_DEFAULT_NOTIFICATION_FILE,
+    API_DEFAULT_PORT, API_DEFAULT_SESSION_IDLE_LIFETIME, API_DEFAULT_SESSION_MAX_LIFETIME,
+    API_DEFAULT_TRUSTED_PROXY_HOPS, API_DEFAULT_UPLOAD_DIR, API_DEFAULT_USERNAME_CHANGE_COOLDOWN,
+};
+use anyhow::{bail, Context, Result};
+use argon2::Params;
 use http::header;
-use std::{env, net::SocketAddr};
+use std::{env, net::SocketAddr, path::PathBuf, time::Duration};
 use tracing::*;
 
+#[derive(Clone, Debug, PartialEq)]
+pub enum RegistrationMode {
+    Open,
+    Closed,
+    /// signing up requires an invite code created by an existing user.
+    InviteOnly,
+}
+
 #[derive(Clone, Debug)]
 pub struct ApiConfig {
     pub database_url: String,
     pub socket_address: SocketAddr,
     pub db_name: String,
     pub cors_origins: Vec<header::HeaderValue>,
-    // pub argon_params: Params,
+    pub upload_dir: PathBuf,
+    /// max size of a single attachment in bytes.
+    pub max_attachment_size: usize,
+    pub attachment_mime_types: Vec<String>,
+    /// how long a session stays valid without being used.
+    pub session_idle_lifetime: Duration,
+    /// how long a session stays valid after it was created, regardless of use.
+    pub session_max_lifetime: Duration,
+    /// secret used to hash session tokens before they are stored.
+    pub session_token_key: String,
+    /// file the password reset tokens are written to.
+    pub notification_file: PathBuf,
+    /// failed logins for a username before it gets locked out.
+    pub login_max_attempts_per_username: u32,
+    /// failed logins from an ip address before it gets locked out.
+    pub login_max_attempts_per_ip: u32,
+    /// first lockout duration, doubled with every further failed login.
+    pub login_base_lockout: Duration,
+    pub login_max_lockout: Duration,
+    /// whether to trust the `X-Forwarded-For` header for client ip addresses.
+    pub trust_proxy: bool,
+    /// number of proxies in front of the api. the client ip is taken this many entries from the
+    /// right of `X-Forwarded-For`, entries further left are set by the client.
+    pub trusted_proxy_hops: usize,
+    pub registration_mode: RegistrationMode,
+    /// minimum time between username changes, zero disables the cooldown.
+    pub username_change_cooldown: Duration,
+    pub argon_params: Params,
 }
 
 impl ApiConfig {
@@ -45,36 +87,200 @@ impl ApiConfig {
             API_DEFAULT_PORT.into()
         });
 
-        // let argon_m_cost = match env::var("ARGON_M_COST") {
-        //     Ok(val) => val.parse().context("Failed to parse ARGON_M_COST as int.")?,
-        //     Err(_) => {
-        //         debug!("ARGON_M_COST variable is not set. using default: {}", Params::DEFAULT_M_COST);
-        //         Params::DEFAULT_M_COST
-        //     }
-        // };
-
-        // let argon_t_cost = match env::var("ARGON_T_COST") {
-        //     Ok(val) => val.parse().context("Failed to parse ARGON_T_COST as int.")?,
-        //     Err(_) => {
-        //         debug!("ARGON_T_COST variable is not set. using default: {}", Params::DEFAULT_T_COST);
-        //         Params::DEFAULT_T_COST
-        //     }
-        // };
-
-        // let argon_p_cost = match env::var("ARGON_P_COST") {
-        //     Ok(val) => val.parse().context("Failed to parse ARGON_P_COST as int.")?,
-        //     Err(_) => {
-        //         debug!("ARGON_P_COST variable is not set. using default: {}", Params::DEFAULT_P_COST);
-        //         Params::DEFAULT_P_COST
-        //     }
-        // };
-
-        // let argon_params = match Params::new(argon_m_cost, argon_t_cost, argon_p_cost, None) {
-        //     Ok(params) => params,
-        //     Err(error) => {
-        //         bail!("Failed to construct argon2 params: {}",error)
-        //     }
-        // };
+        let upload_dir = env::var("UPLOAD_DIR").unwrap_or_else(|_| {
+            debug!("UPLOAD_DIR variable is not set. using default: {API_DEFAULT_UPLOAD_DIR}");
+            API_DEFAULT_UPLOAD_DIR.into()
+        });
+
+        let max_attachment_size = match env::var("MAX_ATTACHMENT_SIZE") {
+            Ok(val) => val
+                .parse()
+                .context("Failed to parse MAX_ATTACHMENT_SIZE as int.")?,
+            Err(_) => {
+                debug!(
+                    "MAX_ATTACHMENT_SIZE variable is not set. using default: {API_DEFAULT_MAX_ATTACHMENT_SIZE}"
+                );
+                API_DEFAULT_MAX_ATTACHMENT_SIZE
+            }
+        };
+
+        let attachment_mime_types = env::var("ATTACHMENT_MIME_TYPES")
+            .unwrap_or_else(|_| {
+                debug!("ATTACHMENT_MIME_TYPES variable is not set. using default: {API_DEFAULT_ATTACHMENT_MIME_TYPES}");
+                API_DEFAULT_ATTACHMENT_MIME_TYPES.into()
+            })
+            .split_whitespace()
+            .map(|mime_type| mime_type.to_ascii_lowercase())
+            .collect();
+
+        let notification_file = env::var("NOTIFICATION_FILE").unwrap_or_else(|_| {
+            debug!(
+                "NOTIFICATION_FILE variable is not set. using default: {API_DEFAULT_NOTIFICATION_FILE}"
+            );
+            API_DEFAULT_NOTIFICATION_FILE.into()
+        });
+
+        let login_max_attempts_per_username = match env::var("LOGIN_MAX_ATTEMPTS") {
+            Ok(val) => val
+                .parse()
+                .context("Failed to parse LOGIN_MAX_ATTEMPTS as int.")?,
+            Err(_) => {
+                debug!(
+                    "LOGIN_MAX_ATTEMPTS variable is not set. using default: {API_DEFAULT_LOGIN_MAX_ATTEMPTS_PER_USERNAME}"
+                );
+                API_DEFAULT_LOGIN_MAX_ATTEMPTS_PER_USERNAME
+            }
+        };
+
+        let login_max_attempts_per_ip = match env::var("LOGIN_MAX_ATTEMPTS_PER_IP") {
+            Ok(val) => val
+                .parse()
+                .context("Failed to parse LOGIN_MAX_ATTEMPTS_PER_IP as int.")?,
+            Err(_) => {
+                debug!(
+                    "LOGIN_MAX_ATTEMPTS_PER_IP variable is not set. using default: {API_DEFAULT_LOGIN_MAX_ATTEMPTS_PER_IP}"
+                );
+                API_DEFAULT_LOGIN_MAX_ATTEMPTS_PER_IP
+            }
+        };
+
+        let login_base_lockout = match env::var("LOGIN_BASE_LOCKOUT") {
+            Ok(val) => val
+                .parse()
+                .context("Failed to parse LOGIN_BASE_LOCKOUT as int.")?,
+            Err(_) => {
+                debug!(
+                    "LOGIN_BASE_LOCKOUT variable is not set. using default: {API_DEFAULT_LOGIN_BASE_LOCKOUT}"
+                );
+                API_DEFAULT_LOGIN_BASE_LOCKOUT
+            }
+        };
+
+        let login_max_lockout = match env::var("LOGIN_MAX_LOCKOUT") {
+            Ok(val) => val
+                .parse()
+                .context("Failed to parse LOGIN_MAX_LOCKOUT as int.")?,
+            Err(_) => {
+                debug!(
+                    "LOGIN_MAX_LOCKOUT variable is not set. using default: {API_DEFAULT_LOGIN_MAX_LOCKOUT}"
+                );
+                API_DEFAULT_LOGIN_MAX_LOCKOUT
+            }
+        };
+
+        let trusted_proxy_hops = match env::var("TRUSTED_PROXY_HOPS") {
+            Ok(val) => val
+                .parse()
+                .context("Failed to parse TRUSTED_PROXY_HOPS as int.")?,
+            Err(_) => {
+                debug!(
+                    "TRUSTED_PROXY_HOPS variable is not set. using default: {API_DEFAULT_TRUSTED_PROXY_HOPS}"
+                );
+                API_DEFAULT_TRUSTED_PROXY_HOPS
+            }
+        };
+        if trusted_proxy_hops == 0 {
+            bail!("TRUSTED_PROXY_HOPS must be atleast 1.");
+        }
+
+        let session_idle_lifetime = match env::var("SESSION_IDLE_LIFETIME") {
+            Ok(val) => val
+                .parse()
+                .context("Failed to parse SESSION_IDLE_LIFETIME as int.")?,
+            Err(_) => {
+                debug!(
+                    "SESSION_IDLE_LIFETIME variable is not set. using default: {API_DEFAULT_SESSION_IDLE_LIFETIME}"
+                );
+                API_DEFAULT_SESSION_IDLE_LIFETIME
+            }
+        };
+
+        let session_max_lifetime = match env::var("SESSION_MAX_LIFETIME") {
+            Ok(val) => val
+                .parse()
+                .context("Failed to parse SESSION_MAX_LIFETIME as int.")?,
+            Err(_) => {
+                debug!(
+                    "SESSION_MAX_LIFETIME variable is not set. using default: {API_DEFAULT_SESSION_MAX_LIFETIME}"
+                );
+                API_DEFAULT_SESSION_MAX_LIFETIME
+            }
+        };
+
+        let argon_m_cost = match env::var("ARGON_M_COST") {
+            Ok(val) => val
+                .parse()
+                .context("Failed to parse ARGON_M_COST as int.")?,
+            Err(_) => {
+                debug!(
+                    "ARGON_M_COST variable is not set. using default: {}",
+                    Params::DEFAULT_M_COST
+                );
+                Params::DEFAULT_M_COST
+            }
+        };
+
+        let argon_t_cost = match env::var("ARGON_T_COST") {
+            Ok(val) => val
+                .parse()
+                .context("Failed to parse ARGON_T_COST as int.")?,
+            Err(_) => {
+                debug!(
+                    "ARGON_T_COST variable is not set. using default: {}",
+                    Params::DEFAULT_T_COST
+                );
+                Params::DEFAULT_T_COST
+            }
+        };
+
+        let argon_p_cost = match env::var("ARGON_P_COST") {
+            Ok(val) => val
+                .parse()
+                .context("Failed to parse ARGON_P_COST as int.")?,
+            Err(_) => {
+                debug!(
+                    "ARGON_P_COST variable is not set. using default: {}",
+                    Params::DEFAULT_P_COST
+                );
+                Params::DEFAULT_P_COST
+            }
+        };
+
+        let argon_params = match Params::new(argon_m_cost, argon_t_cost, argon_p_cost, None) {
+            Ok(params) => params,
+            Err(error) => {
+                bail!("Failed to construct argon2 params: {}", error)
+            }
+        };
+
+        let username_change_cooldown = match env::var("USERNAME_CHANGE_COOLDOWN") {
+            Ok(val) => val
+                .parse()
+                .context("Failed to parse USERNAME_CHANGE_COOLDOWN as int.")?,
+            Err(_) => {
+                debug!(
+                    "USERNAME_CHANGE_COOLDOWN variable is not set. using default: {API_DEFAULT_USERNAME_CHANGE_COOLDOWN}"
+                );
+                API_DEFAULT_USERNAME_CHANGE_COOLDOWN
+            }
+        };
+
+        // DISABLE_SIGNUP is kept for compatibility, REGISTRATION_MODE takes precedence.
+        let registration_mode = match env::var("REGISTRATION_MODE") {
+            Ok(val) => match val.to_lowercase().as_str() {
+                "open" => RegistrationMode::Open,
+                "closed" => RegistrationMode::Closed,
+                "invite" | "invite-only" => RegistrationMode::InviteOnly,
+                _ => bail!(
+                    "Invalid REGISTRATION_MODE: {val}. expected one of: open, closed, invite-only."
+                ),
+            },
+            Err(_) if is_truthy_var("DISABLE_SIGNUP") => RegistrationMode::Closed,
+            Err(_) => {
+                debug!("REGISTRATION_MODE variable is not set. using default: open");
+                RegistrationMode::Open
+            }
+        };
 
         let mut cors_origins: Vec<header::HeaderValue> = Vec::new();
         for origin in env::var("CORS_ORIGINS").unwrap_or_default().split(' ') {
@@ -90,8 +296,28 @@ impl ApiConfig {
             cors_origins,
             db_name: env::var("DATABASE_NAME")
                 .context("Missing DATABASE_NAME environment variable.")?,
-            // argon_params,
+            upload_dir: upload_dir.into(),
+            max_attachment_size,
+            attachment_mime_types,
+            session_idle_lifetime: Duration::from_secs(session_idle_lifetime),
+            session_max_lifetime: Duration::from_secs(session_max_lifetime),
+            session_token_key: env::var("SESSION_TOKEN_KEY")
+                .context("Missing SESSION_TOKEN_KEY environment variable.")?,
+            notification_file: notification_file.into(),
+            login_max_attempts_per_username,
+            login_max_attempts_per_ip,
+            login_base_lockout: Duration::from_secs(login_base_lockout),
+            login_max_lockout: Duration::from_secs(login_max_lockout),
+            trust_proxy: is_truthy_var("TRUST_PROXY"),
+            trusted_proxy_hops,
+            registration_mode,
+            username_change_cooldown: Duration::from_secs(username_change_cooldown),
+            argon_params,
         };
         Ok(config)
     }
 }
+
+fn is_truthy_var(key: &str) -> bool {
+    env::var(key).is_ok_and(|val| !val.is_empty() && val != "false" && val != "0")
+}
@@ -4,10 +4,51 @@ use regex::Regex;
 // Internal defaults.
 pub const API_DEFAULT_HOST: &str = "127.0.0.1";
 pub const API_DEFAULT_PORT: &str = "5000";
+pub const API_DEFAULT_UPLOAD_DIR: &str = "uploads";
+pub const API_DEFAULT_MAX_ATTACHMENT_SIZE: usize = 8 * 1024 * 1024;
+pub const API_DEFAULT_ATTACHMENT_MIME_TYPES: &str =
+    "image/png image/jpeg image/gif image/webp application/pdf text/plain";
+/// in seconds.
+pub const API_DEFAULT_SESSION_IDLE_LIFETIME: u64 = 30 * 24 * 60 * 60;
+/// in seconds.
+pub const API_DEFAULT_SESSION_MAX_LIFETIME: u64 = 90 * 24 * 60 * 60;
+pub const API_DEFAULT_NOTIFICATION_FILE: &str = "notifications.log";
+pub const API_DEFAULT_LOGIN_MAX_ATTEMPTS_PER_USERNAME: u32 = 5;
+pub const API_DEFAULT_LOGIN_MAX_ATTEMPTS_PER_IP: u32 = 20;
+/// in seconds.
+pub const API_DEFAULT_LOGIN_BASE_LOCKOUT: u64 = 30;
+/// in seconds.
+pub const API_DEFAULT_LOGIN_MAX_LOCKOUT: u64 = 15 * 60;
+pub const API_DEFAULT_TRUSTED_PROXY_HOPS: usize = 1;
+pub const PASSWORD_RESET_MAX_REQUESTS_PER_USERNAME: u32 = 3;
+pub const PASSWORD_RESET_MAX_REQUESTS_PER_IP: u32 = 10;
+/// in seconds.
+pub const PASSWORD_RESET_WINDOW_S: u64 = 60 * 60;
+/// in seconds.
+pub const API_DEFAULT_USERNAME_CHANGE_COOLDOWN: u64 = 0;
 
 // logging events
 pub const EVENT_SYS_CRASH: &str = "sys_crash";
 pub const EVENT_SYS_INTERNAL_ERROR: &str = "sys_internal_error";
 
 // other
+pub const PASSWORD_RESET_TOKEN_LIFETIME_MS: i64 = 60 * 60 * 1000;
+pub const MFA_CHALLENGE_LIFETIME_MS: i64 = 5 * 60 * 1000;
+pub const MFA_CHALLENGE_MAX_ATTEMPTS: i32 = 5;
+pub const MFA_RECOVERY_CODE_COUNT: usize = 10;
+pub const TOTP_ISSUER: &str = "A-Chat";
+/// shown instead of the username of deleted and deactivated accounts.
+pub const DELETED_USER_NAME: &str = "deleted user";
+pub const INVITE_DEFAULT_LIFETIME_S: i64 = 7 * 24 * 60 * 60;
+pub const TYPING_PERMISSION_CACHE_TTL_S: u64 = 30;
+/// uploads that aren't sent with a message within this time are deleted.
+pub const UNSENT_ATTACHMENT_LIFETIME_MS: i64 = 24 * 60 * 60 * 1000;
+pub const ATTACHMENT_SWEEP_INTERVAL_S: u64 = 60 * 60;
+/// how often the last seen time of visible online users is written, so it survives restarts.
+pub const LAST_SEEN_PERSIST_INTERVAL_S: u64 = 60;
+/// distinct emojis a single message can be reacted with.
+pub const MAX_REACTIONS_PER_MESSAGE: usize = 20;
 pub static USERNAME_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"^[a-zA-Z_0-9\-]*$").unwrap());
+/// custom reactions are shortcodes like `:party_parrot:`.
+pub static REACTION_SHORTCODE_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^:[a-z0-9_+\-]{1,30}:$").unwrap());
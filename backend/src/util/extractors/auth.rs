@@ -9,7 +9,6 @@ use crate::{app::AppState, database::models::session, util::result::ApiError};
 pub struct AuthUser {
     pub id: String,
     pub username: String,
-    pub password_hash: String,
     pub session: session::Session,
 }
 
@@ -21,7 +20,7 @@ impl FromRequestParts<AppState> for AuthUser {
         req: &mut Parts,
         state: &AppState,
     ) -> Result<Self, Self::Rejection> {
-        let db = AppState::from_ref(state).db;
+        let state = AppState::from_ref(state);
         let auth_header = req.headers.get("Authorization");
         let auth_header = match auth_header {
             Some(header) => header,
@@ -37,8 +36,7 @@ impl FromRequestParts<AppState> for AuthUser {
             return Err(ApiError::Unauthorized);
         }
         let token = auth_header[1];
-        let account = session::validate_token(&db, token).await?;
-        // TODO: "Touch" session to update its expiration date
+        let account = session::validate_token(&state.db, &state.config, token).await?;
         Ok(account)
     }
 }
@@ -0,0 +1,106 @@
+use std::net::{IpAddr, SocketAddr};
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+};
+use http::{request::Parts, HeaderMap};
+
+use crate::{app::AppState, util::result::ApiError};
+
+/// ip address of the client. the `X-Forwarded-For` header is only used when `TRUST_PROXY` is set.
+/// proxies append to the header, so only the entry added by the outermost trusted proxy is used.
+pub struct ClientIp(pub IpAddr);
+
+#[async_trait]
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        req: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        if state.config.trust_proxy {
+            if let Some(ip) = forwarded_ip(&req.headers, state.config.trusted_proxy_hops) {
+                return Ok(ClientIp(ip));
+            }
+        }
+
+        let ConnectInfo(addr) = ConnectInfo::<SocketAddr>::from_request_parts(req, state)
+            .await
+            .map_err(|rejection| {
+                anyhow::anyhow!("ClientIp: Missing connection info: {rejection}")
+            })?;
+        Ok(ClientIp(addr.ip()))
+    }
+}
+
+/// returns the `trusted_proxy_hops`th entry of `X-Forwarded-For` counted from the right.
+/// entries left of it can be set by the client and are ignored.
+fn forwarded_ip(headers: &HeaderMap, trusted_proxy_hops: usize) -> Option<IpAddr> {
+    // a header sent several times is the same as one comma separated header.
+    let values = headers
+        .get_all("X-Forwarded-For")
+        .iter()
+        .map(|value| value.to_str().ok())
+        .collect::<Option<Vec<_>>>()?;
+
+    values
+        .iter()
+        .rev()
+        .flat_map(|value| value.rsplit(','))
+        .nth(trusted_proxy_hops.checked_sub(1)?)
+        .and_then(|ip| ip.trim().parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append("X-Forwarded-For", value.parse().unwrap());
+        }
+        headers
+    }
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn missing_header() {
+        assert_eq!(forwarded_ip(&headers(&[]), 1), None);
+    }
+
+    #[test]
+    fn ignores_spoofed_left_entries() {
+        let headers = headers(&["6.6.6.6, 7.7.7.7, 1.2.3.4"]);
+        assert_eq!(forwarded_ip(&headers, 1), ip("1.2.3.4"));
+    }
+
+    #[test]
+    fn counts_trusted_proxies_from_the_right() {
+        let headers = headers(&["6.6.6.6, 1.2.3.4, 10.0.0.1"]);
+        assert_eq!(forwarded_ip(&headers, 2), ip("1.2.3.4"));
+        assert_eq!(forwarded_ip(&headers, 3), ip("6.6.6.6"));
+        // fewer entries than proxies means the header didn't pass through all of them.
+        assert_eq!(forwarded_ip(&headers, 4), None);
+    }
+
+    #[test]
+    fn joins_repeated_headers() {
+        let headers = headers(&["6.6.6.6", "1.2.3.4,10.0.0.1"]);
+        assert_eq!(forwarded_ip(&headers, 2), ip("1.2.3.4"));
+        assert_eq!(forwarded_ip(&headers, 3), ip("6.6.6.6"));
+    }
+
+    #[test]
+    fn rejects_malformed_entries() {
+        assert_eq!(forwarded_ip(&headers(&["1.2.3.4, garbage"]), 1), None);
+        assert_eq!(forwarded_ip(&headers(&["1.2.3.4,"]), 1), None);
+        assert_eq!(forwarded_ip(&headers(&["1.2.3.4:80"]), 1), None);
+        assert_eq!(forwarded_ip(&headers(&[" ::1 "]), 1), ip("::1"));
+    }
+}
@@ -1,3 +1,4 @@
 pub mod auth;
+pub mod client_ip;
 pub mod json;
 pub mod query;
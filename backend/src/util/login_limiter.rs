@@ -0,0 +1,302 @@
+use std::{
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+
+use super::{
+    config::ApiConfig,
+    constants::{
+        PASSWORD_RESET_MAX_REQUESTS_PER_IP, PASSWORD_RESET_MAX_REQUESTS_PER_USERNAME,
+        PASSWORD_RESET_WINDOW_S,
+    },
+    result::{ApiError, ApiResult},
+};
+
+/// entries are pruned once the map grows past this size.
+const MAX_TRACKED_KEYS: usize = 10_000;
+
+#[derive(Default)]
+struct FailedAttempts {
+    count: u32,
+    last_failure: Option<Instant>,
+    locked_until: Option<Instant>,
+}
+
+/// requests counted since `started`.
+struct RequestWindow {
+    count: u32,
+    started: Instant,
+}
+
+/// tracks failed logins per username and per ip address. failed second factors are tracked per user id.
+/// after `max_attempts` failures the key is locked out, the lockout doubles with every further failure.
+/// password reset requests are limited separately, per username and ip within a fixed window.
+pub struct LoginLimiter {
+    attempts: DashMap<String, FailedAttempts>,
+    password_resets: DashMap<String, RequestWindow>,
+    max_attempts_per_username: u32,
+    max_attempts_per_ip: u32,
+    base_lockout: Duration,
+    max_lockout: Duration,
+}
+
+impl LoginLimiter {
+    pub fn new(config: &ApiConfig) -> Self {
+        Self {
+            attempts: DashMap::new(),
+            password_resets: DashMap::new(),
+            max_attempts_per_username: config.login_max_attempts_per_username,
+            max_attempts_per_ip: config.login_max_attempts_per_ip,
+            base_lockout: config.login_base_lockout,
+            max_lockout: config.login_max_lockout,
+        }
+    }
+
+    fn username_key(username: &str) -> String {
+        format!("user:{}", username.to_lowercase())
+    }
+
+    fn mfa_key(user_id: &str) -> String {
+        format!("mfa:{user_id}")
+    }
+
+    fn keys(account_key: String, ip: IpAddr) -> [(String, bool); 2] {
+        [(account_key, true), (format!("ip:{ip}"), false)]
+    }
+
+    /// failures are forgotten once no new one happened for `max_lockout`.
+    fn is_stale(&self, attempts: &FailedAttempts, now: Instant) -> bool {
+        attempts
+            .last_failure
+            .is_none_or(|last| now.duration_since(last) > self.max_lockout)
+    }
+
+    /// returns `TooManyLoginAttempts` if the username or the ip is locked out.
+    pub fn check(&self, username: &str, ip: IpAddr) -> ApiResult<()> {
+        self.check_keys(Self::username_key(username), ip)
+    }
+
+    /// returns `TooManyLoginAttempts` if second factors of the user or the ip are locked out.
+    pub fn check_mfa(&self, user_id: &str, ip: IpAddr) -> ApiResult<()> {
+        self.check_keys(Self::mfa_key(user_id), ip)
+    }
+
+    /// counts a password reset request for `username` from `ip`. returns `TooManyPasswordResets`
+    /// once either reached its limit in the current window, rejected requests aren't counted.
+    pub fn check_password_reset(&self, username: &str, ip: IpAddr) -> ApiResult<()> {
+        let now = Instant::now();
+        let window = Duration::from_secs(PASSWORD_RESET_WINDOW_S);
+
+        if self.password_resets.len() > MAX_TRACKED_KEYS {
+            self.password_resets
+                .retain(|_, requests| now.duration_since(requests.started) < window);
+        }
+
+        let keys = [
+            (
+                format!("reset-user:{}", username.to_lowercase()),
+                PASSWORD_RESET_MAX_REQUESTS_PER_USERNAME,
+            ),
+            (format!("reset-ip:{ip}"), PASSWORD_RESET_MAX_REQUESTS_PER_IP),
+        ];
+
+        let mut retry_after = Duration::ZERO;
+        for (key, max_requests) in &keys {
+            if let Some(requests) = self.password_resets.get(key) {
+                let elapsed = now.duration_since(requests.started);
+                if elapsed < window && requests.count >= *max_requests {
+                    retry_after = retry_after.max(window - elapsed);
+                }
+            }
+        }
+        if !retry_after.is_zero() {
+            return Err(ApiError::TooManyPasswordResets(round_up_secs(retry_after)));
+        }
+
+        for (key, _) in keys {
+            let mut requests = self.password_resets.entry(key).or_insert(RequestWindow {
+                count: 0,
+                started: now,
+            });
+            if now.duration_since(requests.started) >= window {
+                *requests = RequestWindow {
+                    count: 0,
+                    started: now,
+                };
+            }
+            requests.count += 1;
+        }
+        Ok(())
+    }
+
+    fn check_keys(&self, account_key: String, ip: IpAddr) -> ApiResult<()> {
+        let now = Instant::now();
+        let mut retry_after = Duration::ZERO;
+
+        for (key, _) in Self::keys(account_key, ip) {
+            if let Some(attempts) = self.attempts.get(&key) {
+                if let Some(locked_until) = attempts.locked_until {
+                    retry_after = retry_after.max(locked_until.saturating_duration_since(now));
+                }
+            }
+        }
+
+        if retry_after.is_zero() {
+            Ok(())
+        } else {
+            Err(ApiError::TooManyLoginAttempts(round_up_secs(retry_after)))
+        }
+    }
+
+    pub fn record_failure(&self, username: &str, ip: IpAddr) {
+        self.record_key_failure(Self::username_key(username), ip);
+    }
+
+    /// records an incorrect second factor. these count against the ip like failed passwords.
+    pub fn record_mfa_failure(&self, user_id: &str, ip: IpAddr) {
+        self.record_key_failure(Self::mfa_key(user_id), ip);
+    }
+
+    fn record_key_failure(&self, account_key: String, ip: IpAddr) {
+        let now = Instant::now();
+
+        if self.attempts.len() > MAX_TRACKED_KEYS {
+            self.attempts
+                .retain(|_, attempts| !self.is_stale(attempts, now));
+        }
+
+        for (key, is_username) in Self::keys(account_key, ip) {
+            let max_attempts = if is_username {
+                self.max_attempts_per_username
+            } else {
+                self.max_attempts_per_ip
+            };
+
+            let mut attempts = self.attempts.entry(key).or_default();
+            if self.is_stale(&attempts, now) {
+                *attempts = FailedAttempts::default();
+            }
+            attempts.count += 1;
+            attempts.last_failure = Some(now);
+
+            if attempts.count >= max_attempts {
+                let exponent = (attempts.count - max_attempts).min(16);
+                let lockout = self
+                    .base_lockout
+                    .saturating_mul(1 << exponent)
+                    .min(self.max_lockout);
+                attempts.locked_until = Some(now + lockout);
+            }
+        }
+    }
+
+    /// forgets the failures of the user once the whole login succeeded. failures of the ip are kept.
+    pub fn record_success(&self, username: &str, user_id: &str) {
+        self.attempts.remove(&Self::username_key(username));
+        self.attempts.remove(&Self::mfa_key(user_id));
+    }
+}
+
+/// rounding up so clients don't retry a moment too early.
+fn round_up_secs(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+
+    fn limiter() -> LoginLimiter {
+        LoginLimiter {
+            attempts: DashMap::new(),
+            password_resets: DashMap::new(),
+            max_attempts_per_username: 3,
+            max_attempts_per_ip: 10,
+            base_lockout: Duration::from_secs(30),
+            max_lockout: Duration::from_secs(100),
+        }
+    }
+
+    fn lockout(limiter: &LoginLimiter, key: &str) -> Option<Duration> {
+        let attempts = limiter.attempts.get(key)?;
+        Some(
+            attempts
+                .locked_until?
+                .duration_since(attempts.last_failure?),
+        )
+    }
+
+    #[test]
+    fn locks_out_after_max_attempts() {
+        let limiter = limiter();
+        for _ in 0..2 {
+            limiter.record_failure("alice", IP);
+            assert!(limiter.check("alice", IP).is_ok());
+        }
+        limiter.record_failure("Alice", IP);
+        assert!(matches!(
+            limiter.check("alice", IP),
+            Err(ApiError::TooManyLoginAttempts(30))
+        ));
+        // the ip is below its own limit, so other usernames can still log in.
+        assert!(limiter.check("bob", IP).is_ok());
+    }
+
+    #[test]
+    fn lockout_doubles_up_to_max() {
+        let limiter = limiter();
+        let expected = [None, None, Some(30), Some(60), Some(100), Some(100)];
+        for expected in expected {
+            limiter.record_failure("alice", IP);
+            assert_eq!(
+                lockout(&limiter, "user:alice"),
+                expected.map(Duration::from_secs)
+            );
+        }
+    }
+
+    #[test]
+    fn success_resets_account_but_not_ip() {
+        let limiter = limiter();
+        for _ in 0..3 {
+            limiter.record_failure("alice", IP);
+            limiter.record_mfa_failure("id", IP);
+        }
+        limiter.record_success("alice", "id");
+
+        assert!(limiter.check("alice", IP).is_ok());
+        assert!(limiter.check_mfa("id", IP).is_ok());
+        assert_eq!(limiter.attempts.get("ip:127.0.0.1").unwrap().count, 6);
+    }
+
+    #[test]
+    fn password_resets_are_limited_per_username() {
+        let limiter = limiter();
+        for _ in 0..PASSWORD_RESET_MAX_REQUESTS_PER_USERNAME {
+            assert!(limiter.check_password_reset("alice", IP).is_ok());
+        }
+        assert!(matches!(
+            limiter.check_password_reset("alice", IP),
+            Err(ApiError::TooManyPasswordResets(_))
+        ));
+        assert!(limiter.check_password_reset("bob", IP).is_ok());
+    }
+
+    #[test]
+    fn password_resets_do_not_lock_out_logins() {
+        let limiter = limiter();
+        for i in 0..PASSWORD_RESET_MAX_REQUESTS_PER_IP {
+            assert!(limiter
+                .check_password_reset(&format!("user{i}"), IP)
+                .is_ok());
+        }
+        assert!(limiter.check_password_reset("alice", IP).is_err());
+
+        assert!(limiter.check("alice", IP).is_ok());
+        assert!(limiter.check_mfa("id", IP).is_ok());
+    }
+}
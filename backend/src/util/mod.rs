@@ -1,4 +1,7 @@
 pub mod config;
 pub mod constants;
 pub mod extractors;
+pub mod login_limiter;
+pub mod password;
+pub mod permissions;
 pub mod result;
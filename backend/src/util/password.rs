@@ -0,0 +1,104 @@
+use anyhow::{Context, Result};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+
+use super::config::ApiConfig;
+
+fn argon2(params: &Params) -> Argon2<'static> {
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params.clone())
+}
+
+fn is_bcrypt_hash(hash: &str) -> bool {
+    hash.starts_with("$2")
+}
+
+/// hashes the password with argon2id using the configured cost parameters.
+pub fn hash_password(config: &ApiConfig, password: &str) -> Result<String> {
+    hash_password_with(&config.argon_params, password)
+}
+
+fn hash_password_with(params: &Params, password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = argon2(params)
+        .hash_password(password.as_bytes(), &salt)
+        .context("hash_password: argon2 hashing failed")?;
+    Ok(hash.to_string())
+}
+
+/// accepts argon2 hashes and bcrypt hashes created before the switch to argon2.
+pub fn verify_password(config: &ApiConfig, password: &str, hash: &str) -> Result<bool> {
+    if is_bcrypt_hash(hash) {
+        return bcrypt::verify(password, hash)
+            .context("verify_password: bcrypt verification failed");
+    }
+
+    let parsed_hash =
+        PasswordHash::new(hash).context("verify_password: Failed to parse password hash")?;
+    match argon2(&config.argon_params).verify_password(password.as_bytes(), &parsed_hash) {
+        Ok(()) => Ok(true),
+        Err(argon2::password_hash::Error::Password) => Ok(false),
+        Err(err) => Err(err).context("verify_password: argon2 verification failed"),
+    }
+}
+
+/// whether the hash should be replaced with one using argon2id and the current cost parameters.
+pub fn needs_rehash(config: &ApiConfig, hash: &str) -> bool {
+    needs_rehash_with(&config.argon_params, hash)
+}
+
+/// only the cost parameters are compared, the output length isn't part of the configured params.
+fn needs_rehash_with(params: &Params, hash: &str) -> bool {
+    if is_bcrypt_hash(hash) {
+        return true;
+    }
+
+    let Ok(parsed_hash) = PasswordHash::new(hash) else {
+        return true;
+    };
+    if parsed_hash.algorithm != Algorithm::Argon2id.ident()
+        || parsed_hash.version != Some(Version::V0x13.into())
+    {
+        return true;
+    }
+
+    match Params::try_from(&parsed_hash) {
+        Ok(hash_params) => {
+            hash_params.m_cost() != params.m_cost()
+                || hash_params.t_cost() != params.t_cost()
+                || hash_params.p_cost() != params.p_cost()
+        }
+        Err(_) => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(m_cost: u32) -> Params {
+        Params::new(m_cost, Params::DEFAULT_T_COST, Params::DEFAULT_P_COST, None).unwrap()
+    }
+
+    #[test]
+    fn fresh_hash_does_not_need_rehash() {
+        let hash = hash_password_with(&params(Params::DEFAULT_M_COST), "hunter22").unwrap();
+        assert!(!needs_rehash_with(&params(Params::DEFAULT_M_COST), &hash));
+    }
+
+    #[test]
+    fn changed_cost_needs_rehash() {
+        let hash = hash_password_with(&params(Params::DEFAULT_M_COST), "hunter22").unwrap();
+        assert!(needs_rehash_with(
+            &params(Params::DEFAULT_M_COST * 2),
+            &hash
+        ));
+    }
+
+    #[test]
+    fn bcrypt_hash_needs_rehash() {
+        let hash = bcrypt::hash("hunter22", 4).unwrap();
+        assert!(needs_rehash_with(&params(Params::DEFAULT_M_COST), &hash));
+    }
+}
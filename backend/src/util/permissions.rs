@@ -0,0 +1,198 @@
+use bitflags::bitflags;
+
+use crate::database::models::{
+    chat::{Chat, ChatRole, ChatType},
+    user::{Relation, RelationStatus},
+};
+
+use super::result::{ApiError, ApiResult};
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ChatPermissions: u32 {
+        const READ_MESSAGES = 1 << 0;
+        const SEND_MESSAGES = 1 << 1;
+        const MANAGE_MEMBERS = 1 << 2;
+        const DELETE_MESSAGES = 1 << 3;
+        const PIN_MESSAGES = 1 << 4;
+        const MANAGE_ROLES = 1 << 5;
+    }
+}
+
+impl ChatPermissions {
+    /// returns the matching permission error if any of the given permissions are missing.
+    pub fn require(&self, permissions: ChatPermissions) -> ApiResult<()> {
+        let missing = permissions - *self;
+
+        if missing.is_empty() {
+            Ok(())
+        } else if missing.contains(ChatPermissions::READ_MESSAGES) {
+            Err(ApiError::ChatReadPermissionDenied)
+        } else if missing.contains(ChatPermissions::SEND_MESSAGES) {
+            Err(ApiError::ChatWritePermissionDenied)
+        } else {
+            Err(ApiError::ChatManagePermissionDenied)
+        }
+    }
+}
+
+impl ChatRole {
+    pub fn permissions(&self) -> ChatPermissions {
+        match self {
+            ChatRole::Owner => ChatPermissions::all(),
+            ChatRole::Moderator => {
+                ChatPermissions::READ_MESSAGES
+                    | ChatPermissions::SEND_MESSAGES
+                    | ChatPermissions::MANAGE_MEMBERS
+                    | ChatPermissions::DELETE_MESSAGES
+                    | ChatPermissions::PIN_MESSAGES
+            }
+            ChatRole::Member => ChatPermissions::READ_MESSAGES | ChatPermissions::SEND_MESSAGES,
+        }
+    }
+}
+
+/// resolves what `user_id` is allowed to do in `chat`.
+/// `relations` are the relations of `user_id`, they are only needed for direct chats.
+pub fn resolve_chat_permissions(
+    chat: &Chat,
+    user_id: &str,
+    relations: &[Relation],
+) -> ChatPermissions {
+    let recipient = match chat.recipients.iter().find(|r| r.id == user_id) {
+        Some(recipient) => recipient,
+        None => return ChatPermissions::empty(),
+    };
+
+    match chat.chat_type {
+        ChatType::Group => recipient
+            .role
+            .as_ref()
+            .unwrap_or(&ChatRole::Member)
+            .permissions(),
+        ChatType::Direct => {
+            let is_friend = chat.recipients.len() == 2
+                && chat
+                    .recipients
+                    .iter()
+                    .filter(|r| r.id != user_id)
+                    .all(|other| {
+                        relations
+                            .iter()
+                            .any(|r| r.id == other.id && r.status == RelationStatus::Friend)
+                    });
+
+            if is_friend {
+                ChatPermissions::READ_MESSAGES | ChatPermissions::SEND_MESSAGES
+            } else {
+                ChatPermissions::READ_MESSAGES
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::models::chat::ChatRecipient;
+
+    fn recipient(id: &str, role: Option<ChatRole>) -> ChatRecipient {
+        ChatRecipient {
+            id: id.to_string(),
+            role,
+            last_read_id: None,
+        }
+    }
+
+    fn chat(chat_type: ChatType, recipients: Vec<ChatRecipient>) -> Chat {
+        Chat {
+            id: "chat".to_string(),
+            chat_type,
+            name: None,
+            owner_id: None,
+            recipients,
+            last_message_id: None,
+        }
+    }
+
+    fn group() -> Chat {
+        chat(
+            ChatType::Group,
+            vec![
+                recipient("owner", Some(ChatRole::Owner)),
+                recipient("member", None),
+                recipient("moderator", Some(ChatRole::Moderator)),
+            ],
+        )
+    }
+
+    #[test]
+    fn owner_has_all_permissions() {
+        assert_eq!(
+            resolve_chat_permissions(&group(), "owner", &[]),
+            ChatPermissions::all()
+        );
+    }
+
+    #[test]
+    fn member_has_default_permissions() {
+        let permissions = resolve_chat_permissions(&group(), "member", &[]);
+        assert_eq!(
+            permissions,
+            ChatPermissions::READ_MESSAGES | ChatPermissions::SEND_MESSAGES
+        );
+        assert!(matches!(
+            permissions.require(ChatPermissions::MANAGE_MEMBERS),
+            Err(ApiError::ChatManagePermissionDenied)
+        ));
+    }
+
+    #[test]
+    fn member_role_overrides_default_permissions() {
+        let permissions = resolve_chat_permissions(&group(), "moderator", &[]);
+        assert!(permissions
+            .require(ChatPermissions::MANAGE_MEMBERS | ChatPermissions::DELETE_MESSAGES)
+            .is_ok());
+        assert!(matches!(
+            permissions.require(ChatPermissions::MANAGE_ROLES),
+            Err(ApiError::ChatManagePermissionDenied)
+        ));
+    }
+
+    #[test]
+    fn non_recipient_has_no_permissions() {
+        let permissions = resolve_chat_permissions(&group(), "stranger", &[]);
+        assert!(permissions.is_empty());
+        assert!(matches!(
+            permissions.require(ChatPermissions::READ_MESSAGES),
+            Err(ApiError::ChatReadPermissionDenied)
+        ));
+    }
+
+    #[test]
+    fn direct_chat_is_read_only_without_friendship() {
+        let direct = chat(
+            ChatType::Direct,
+            vec![recipient("a", None), recipient("b", None)],
+        );
+        let friend = Relation {
+            id: "b".to_string(),
+            status: RelationStatus::Friend,
+        };
+        let blocked = Relation {
+            id: "b".to_string(),
+            status: RelationStatus::Blocked,
+        };
+
+        assert_eq!(
+            resolve_chat_permissions(&direct, "a", &[friend]),
+            ChatPermissions::READ_MESSAGES | ChatPermissions::SEND_MESSAGES
+        );
+        let permissions = resolve_chat_permissions(&direct, "a", &[blocked]);
+        assert_eq!(permissions, ChatPermissions::READ_MESSAGES);
+        assert!(matches!(
+            permissions.require(ChatPermissions::SEND_MESSAGES),
+            Err(ApiError::ChatWritePermissionDenied)
+        ));
+    }
+}
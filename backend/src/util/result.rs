@@ -4,7 +4,7 @@ use super::constants::EVENT_SYS_INTERNAL_ERROR;
 
 use axum::{
     extract::rejection::{JsonRejection, QueryRejection},
-    http::StatusCode,
+    http::{header, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
@@ -30,9 +30,41 @@ pub enum ApiError {
     BlockedByOtherFriend,
     BlockedFriend,
     CantRemoveSelf,
+    CantBlockSelf,
+    NotBlocked,
     ChatNotFound,
     ChatReadPermissionDenied,
     ChatWritePermissionDenied,
+    NotFriends,
+    NotGroupChat,
+    EmptyGroupChat,
+    AlreadyChatRecipient,
+    ChatManagePermissionDenied,
+    MessageNotFound,
+    NotMessageAuthor,
+    InvalidReaction,
+    TooManyReactions,
+    InvalidReply,
+    InvalidAttachment,
+    AttachmentNotFound,
+    AttachmentTooLarge,
+    UnsupportedAttachmentType,
+    SessionNotFound,
+    IncorrectPassword,
+    InvalidResetToken,
+    MfaAlreadyEnabled,
+    MfaNotEnabled,
+    InvalidMfaCode,
+    InvalidMfaTicket,
+    /// seconds until the next login attempt is allowed.
+    TooManyLoginAttempts(u64),
+    /// seconds until the next password reset request is allowed.
+    TooManyPasswordResets(u64),
+    SignupDisabled,
+    InvalidInviteCode,
+    InviteNotFound,
+    /// seconds until the username can be changed again.
+    UsernameChangeCooldown(u64),
 }
 
 impl From<anyhow::Error> for ApiError {
@@ -78,6 +110,9 @@ impl ApiError {
         match self {
             // TODO: test this by intentionally returning an db error in a route with .context()
             ApiError::UnknownError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::TooManyLoginAttempts(_)
+            | ApiError::TooManyPasswordResets(_)
+            | ApiError::UsernameChangeCooldown(_) => StatusCode::TOO_MANY_REQUESTS,
 
             ApiError::JsonError(error) => match error {
                 JsonRejection::JsonDataError(_) | JsonRejection::JsonSyntaxError(_) => {
@@ -94,12 +129,39 @@ impl ApiError {
             | ApiError::AlreadySentFR
             | ApiError::BlockedByOtherFriend
             | ApiError::BlockedFriend
-            | ApiError::CantRemoveSelf => StatusCode::CONFLICT,
-            ApiError::Unauthorized | ApiError::InvalidCredentials => StatusCode::UNAUTHORIZED,
-            ApiError::UserNotFound | ApiError::ChatNotFound => StatusCode::NOT_FOUND,
-            ApiError::ChatReadPermissionDenied | ApiError::ChatWritePermissionDenied => {
-                StatusCode::FORBIDDEN
+            | ApiError::CantRemoveSelf
+            | ApiError::CantBlockSelf
+            | ApiError::AlreadyChatRecipient
+            | ApiError::MfaAlreadyEnabled => StatusCode::CONFLICT,
+            ApiError::NotGroupChat
+            | ApiError::EmptyGroupChat
+            | ApiError::InvalidReaction
+            | ApiError::TooManyReactions
+            | ApiError::InvalidReply
+            | ApiError::InvalidAttachment
+            | ApiError::InvalidResetToken
+            | ApiError::MfaNotEnabled
+            | ApiError::NotBlocked
+            | ApiError::InvalidInviteCode => StatusCode::BAD_REQUEST,
+            ApiError::AttachmentTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::UnsupportedAttachmentType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::Unauthorized | ApiError::InvalidCredentials | ApiError::InvalidMfaTicket => {
+                StatusCode::UNAUTHORIZED
             }
+            ApiError::UserNotFound
+            | ApiError::ChatNotFound
+            | ApiError::MessageNotFound
+            | ApiError::AttachmentNotFound
+            | ApiError::SessionNotFound
+            | ApiError::InviteNotFound => StatusCode::NOT_FOUND,
+            ApiError::ChatReadPermissionDenied
+            | ApiError::ChatWritePermissionDenied
+            | ApiError::ChatManagePermissionDenied
+            | ApiError::NotMessageAuthor
+            | ApiError::IncorrectPassword
+            | ApiError::InvalidMfaCode
+            | ApiError::SignupDisabled
+            | ApiError::NotFriends => StatusCode::FORBIDDEN,
         }
     }
 
@@ -107,7 +169,9 @@ impl ApiError {
         match self {
             ApiError::UnknownError(_) => "Unknown error occurred.".to_string(),
             ApiError::JsonError(_) => "Invalid JSON request or missing fields.".to_string(),
-            ApiError::QueryStringError(_) => "Invalid query string.".to_string(),
+            ApiError::QueryStringError(rejection) => {
+                format!("Invalid query string: {}", rejection.body_text())
+            }
             ApiError::ValidationError(_) => {
                 "Validation error occurred in the following fields.".to_string()
             }
@@ -125,6 +189,8 @@ impl ApiError {
             ApiError::BlockedByOtherFriend => "You are blocked by this user.".to_string(),
             ApiError::BlockedFriend => "You blocked this user.".to_string(),
             ApiError::CantRemoveSelf => "You can't remove yourself.".to_string(),
+            ApiError::CantBlockSelf => "You can't block yourself.".to_string(),
+            ApiError::NotBlocked => "You haven't blocked this user.".to_string(),
             ApiError::ChatNotFound => "Chat not found.".to_string(),
             ApiError::ChatReadPermissionDenied => {
                 "You don't have permission to read messages of this chat.".to_string()
@@ -132,6 +198,47 @@ impl ApiError {
             ApiError::ChatWritePermissionDenied => {
                 "You don't have permission to send messages in this chat.".to_string()
             }
+            ApiError::NotFriends => "You can only add your friends to a group.".to_string(),
+            ApiError::NotGroupChat => "This action is only available in group chats.".to_string(),
+            ApiError::EmptyGroupChat => {
+                "A group chat needs at least one member besides you.".to_string()
+            }
+            ApiError::AlreadyChatRecipient => "User is already a member of this chat.".to_string(),
+            ApiError::ChatManagePermissionDenied => {
+                "You don't have permission to manage this chat.".to_string()
+            }
+            ApiError::MessageNotFound => "Message not found.".to_string(),
+            ApiError::NotMessageAuthor => "You can only edit your own messages.".to_string(),
+            ApiError::InvalidReaction => "Invalid reaction.".to_string(),
+            ApiError::TooManyReactions => "Message has too many different reactions.".to_string(),
+            ApiError::InvalidReply => {
+                "The message you are replying to doesn't exist in this chat.".to_string()
+            }
+            ApiError::InvalidAttachment => "Invalid or missing attachment.".to_string(),
+            ApiError::AttachmentNotFound => "Attachment not found.".to_string(),
+            ApiError::AttachmentTooLarge => "Attachment is too large.".to_string(),
+            ApiError::UnsupportedAttachmentType => "This type of file is not allowed.".to_string(),
+            ApiError::SessionNotFound => "Session not found.".to_string(),
+            ApiError::IncorrectPassword => "Incorrect password.".to_string(),
+            ApiError::InvalidResetToken => "Invalid or expired reset token.".to_string(),
+            ApiError::MfaAlreadyEnabled => {
+                "Two-factor authentication is already enabled.".to_string()
+            }
+            ApiError::MfaNotEnabled => "Two-factor authentication is not set up.".to_string(),
+            ApiError::InvalidMfaCode => "Invalid authentication code.".to_string(),
+            ApiError::InvalidMfaTicket => "Invalid or expired login ticket.".to_string(),
+            ApiError::SignupDisabled => "Signing up is disabled.".to_string(),
+            ApiError::InvalidInviteCode => "Invalid or expired invite code.".to_string(),
+            ApiError::InviteNotFound => "Invite not found.".to_string(),
+            ApiError::UsernameChangeCooldown(retry_after_s) => {
+                format!("You can change your username again in {retry_after_s} seconds.")
+            }
+            ApiError::TooManyLoginAttempts(retry_after_s) => {
+                format!("Too many failed login attempts. try again in {retry_after_s} seconds.")
+            }
+            ApiError::TooManyPasswordResets(retry_after_s) => {
+                format!("Too many password reset requests. try again in {retry_after_s} seconds.")
+            }
         }
     }
 }
@@ -169,6 +276,15 @@ impl IntoResponse for ApiError {
 
                 self.get_response(Some(validation_errors))
             }
+            Self::TooManyLoginAttempts(retry_after_s)
+            | Self::TooManyPasswordResets(retry_after_s)
+            | Self::UsernameChangeCooldown(retry_after_s) => {
+                let mut response = self.get_response(None);
+                response
+                    .headers_mut()
+                    .insert(header::RETRY_AFTER, HeaderValue::from(retry_after_s));
+                response
+            }
             _ => self.get_response(None),
         }
     }